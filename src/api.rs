@@ -0,0 +1,1190 @@
+use std::fmt;
+
+use futures::Future;
+use futures::future::join_all;
+
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::{self, Json};
+use rocket::tokio::select;
+use rocket::tokio::time::{timeout, Duration, Instant};
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{Catcher, Route, Shutdown, State};
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+use crate::groups::{self, Group, GroupState, GroupStore};
+use crate::light::{HSVColor, LightInfo, MAX_NAME_LENGTH};
+use crate::peripheral::{self, ConnectionState};
+use crate::queue::{CommandOutcome, CommandTicket, DropReason};
+use crate::runner::{self, Light, LightEvent, PeripheralState};
+use crate::scenes::{self, Scene, SceneLight, SceneStore};
+use crate::schedules::{Action, Schedule, Scheduler, Target, Trigger, MAX_SOLAR_OFFSET_MINUTES};
+use crate::store::StoreError;
+use crate::transition;
+use crate::websocket::{Channel, WebSocket};
+
+// MARK: Responses
+
+/// A light as served by the API, `state` is `None` until the light has reported it.
+#[derive(Serialize)]
+pub(crate) struct LightResponse {
+    address: String,
+    name: Option<String>,
+    connected: bool,
+    /// Where the connection stands in more detail, e.g. degraded when the light stopped answering.
+    status: ConnectionState,
+    /// When anything was last heard from the light, in milliseconds since the epoch.
+    last_seen_ms: Option<u64>,
+    state: Option<LightInfo>,
+    /// When `state` was last reported, in milliseconds since the epoch.
+    updated_at_ms: Option<u64>,
+    /// For writes, what became of the command: sent, or superseded by a newer one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<CommandOutcome>,
+    /// For confirmed writes, whether the light reported the new state.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confirmed: Option<bool>,
+    /// Whether the hub is fading the light to a new color.
+    transitioning: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorResponse {
+    error: String,
+    /// For unreachable lights, the state they last reported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_known: Option<LastKnownResponse>,
+    /// For confirmed writes that didn't take, the state the light reported instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reported: Option<LightInfo>,
+}
+
+#[derive(Serialize)]
+struct LastKnownResponse {
+    state: LightInfo,
+    /// How long ago `state` was reported.
+    age_ms: u64,
+}
+
+impl LightResponse {
+    fn new(light: &Light) -> Self {
+        let cached_light_info = light.cached_light_info();
+        LightResponse {
+            address: light.address(),
+            name: cached_light_info.as_ref().map(|(light_info, _)| light_info.name.clone()),
+            connected: light.is_connected(),
+            status: light.connection_state(),
+            last_seen_ms: light.last_seen_ms().map(|timestamp| timestamp as u64),
+            updated_at_ms: cached_light_info.as_ref().map(|(_, timestamp)| *timestamp as u64),
+            state: cached_light_info.map(|(light_info, _)| light_info),
+            command: None,
+            confirmed: None,
+            transitioning: light.is_transitioning(),
+        }
+    }
+
+    /// `confirmed` is `None` unless the write was confirmed.
+    fn after_command(light: &Light, outcome: CommandOutcome, confirmed: Option<bool>) -> Self {
+        LightResponse { command: Some(outcome), confirmed, ..LightResponse::new(light) }
+    }
+}
+
+/// What became of the commands for one light when several are changed at once.
+#[derive(Serialize)]
+pub(crate) struct LightCommandResponse {
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<CommandOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SceneActivationResponse {
+    scene: String,
+    lights: Vec<LightCommandResponse>,
+}
+
+/// A group with its members, members the hub hasn't found (yet) are left out.
+#[derive(Serialize)]
+pub(crate) struct GroupResponse {
+    name: String,
+    state: GroupState,
+    lights: Vec<LightResponse>,
+}
+
+impl GroupResponse {
+    fn new(group: &Group, state: &PeripheralState) -> Self {
+        let members = group.members(state);
+        GroupResponse {
+            name: group.name.clone(),
+            state: groups::aggregate_state(&members),
+            lights: members.iter().map(LightResponse::new).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct GroupCommandResponse {
+    group: String,
+    /// The group's state once every member's command was written.
+    state: GroupState,
+    lights: Vec<LightCommandResponse>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ScheduleResponse {
+    #[serde(flatten)]
+    schedule: Schedule,
+    /// When the schedule runs next, `None` if it's disabled or won't run again.
+    next_run: Option<DateTime<FixedOffset>>,
+}
+
+impl ScheduleResponse {
+    fn new(schedule: Schedule, scheduler: &Scheduler) -> Self {
+        let next_run = scheduler.next_run(&schedule);
+        ScheduleResponse { schedule, next_run }
+    }
+}
+
+// MARK: Requests
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PowerUpdate {
+    on: bool,
+}
+
+/// Any components left out keep their current value.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ColorUpdate {
+    /// Degrees, 0 to 360.
+    h: Option<f64>,
+    /// 0 to 1.
+    s: Option<f64>,
+    /// 0 to 1.
+    v: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NameUpdate {
+    name: String,
+}
+
+/// Creates a schedule or replaces it, a replaced schedule keeps when it last ran.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ScheduleUpdate {
+    trigger: Trigger,
+    action: Action,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+}
+
+/// Fades to a color over `duration_ms`, components left out keep their current value.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TransitionUpdate {
+    h: Option<f64>,
+    s: Option<f64>,
+    v: Option<f64>,
+    duration_ms: u64,
+}
+
+/// Saves the current state of `lights`, given as anything a light can be looked up by.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SceneCreate {
+    name: String,
+    lights: Vec<String>,
+}
+
+/// Saves the current state of the scene's lights again, or of `lights` instead if given.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SceneUpdate {
+    lights: Option<Vec<String>>,
+}
+
+/// The members of a group, given as anything a light can be looked up by.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct GroupUpdate {
+    lights: Vec<String>,
+}
+
+impl ColorUpdate {
+    fn check(&self) -> Result<(), ApiError> {
+        check_range("h", self.h, 360.0)?;
+        check_range("s", self.s, 1.0)?;
+        check_range("v", self.v, 1.0)
+    }
+
+    /// The light's color with the given components changed, it's only asked for its current
+    /// color if some of it is kept.
+    async fn apply_to(&self, light: &Light) -> Result<HSVColor, ApiError> {
+        match (self.h, self.s, self.v) {
+            (Some(h), Some(s), Some(v)) => { Ok(HSVColor { h, s, v }) }
+            _ => { Ok(runner::get_latest_device_info(light).await?.color.with_components(self.h, self.s, self.v)) }
+        }
+    }
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl ScheduleUpdate {
+    /// Checks the update and looks up what its action changes. Lights are saved by address, so
+    /// a schedule keeps changing the same light when lights are found in a different order.
+    fn resolve(&self, state: &PeripheralState, scenes: &SceneStore, groups: &GroupStore, scheduler: &Scheduler) -> Result<Action, ApiError> {
+        self.trigger.check().map_err(|err| ApiError::BadRequest(err.to_string()))?;
+        if let Trigger::Solar { offset_minutes, .. } = &self.trigger {
+            if scheduler.clock().location.is_none() {
+                return Err(ApiError::BadRequest(String::from("Solar triggers need location.latitude and location.longitude in the config")));
+            }
+            if !(-MAX_SOLAR_OFFSET_MINUTES..=MAX_SOLAR_OFFSET_MINUTES).contains(offset_minutes) {
+                return Err(ApiError::BadRequest(format!("offset_minutes must be between -{0} and {0}", MAX_SOLAR_OFFSET_MINUTES)));
+            }
+        }
+
+        let target = match &self.action {
+            Action::Power { target, .. } => { target }
+            Action::Color { target, h, s, v } => {
+                ColorUpdate { h: *h, s: *s, v: *v }.check()?;
+                target
+            }
+            Action::Transition { target, h, s, v, duration_ms } => {
+                TransitionUpdate { h: *h, s: *s, v: *v, duration_ms: *duration_ms }.check()?;
+                target
+            }
+            Action::Scene { scene } => {
+                scenes.get(scene).ok_or_else(|| ApiError::unknown_scene(scene))?;
+                return Ok(self.action.clone());
+            }
+        };
+        let target = match target {
+            Target::Lights(ids) => {
+                Target::Lights(find_lights(ids, state)?.iter().map(|light| light.address()).collect())
+            }
+            Target::Group(name) => {
+                groups.get(name).ok_or_else(|| ApiError::unknown_group(name))?;
+                Target::Group(name.clone())
+            }
+        };
+
+        let mut action = self.action.clone();
+        match &mut action {
+            Action::Power { target: resolved, .. } |
+            Action::Color { target: resolved, .. } |
+            Action::Transition { target: resolved, .. } => { *resolved = target }
+            Action::Scene { .. } => {}
+        }
+
+        Ok(action)
+    }
+}
+
+impl TransitionUpdate {
+    fn check(&self) -> Result<(), ApiError> {
+        self.color().check()?;
+        if self.duration_ms == 0 || Duration::from_millis(self.duration_ms) > transition::MAX_TRANSITION_DURATION {
+            return Err(ApiError::BadRequest(format!("duration_ms must be between 1 and {}", transition::MAX_TRANSITION_DURATION.as_millis())));
+        }
+
+        Ok(())
+    }
+
+    fn color(&self) -> ColorUpdate {
+        ColorUpdate { h: self.h, s: self.s, v: self.v }
+    }
+
+    /// Starts fading `light` to the given color, components left out keep their current value.
+    async fn start(&self, light: &Light) -> Result<(), ApiError> {
+        let current = runner::get_latest_device_info(light).await?;
+        let to = current.color.with_components(self.h, self.s, self.v);
+        transition::start(light, &current, to, Duration::from_millis(self.duration_ms))?;
+
+        Ok(())
+    }
+}
+
+// MARK: Errors
+
+#[derive(Debug)]
+pub(crate) enum ApiError {
+    /// The request body or parameters don't make sense, 400.
+    BadRequest(String),
+    /// No light or route matches, 404.
+    NotFound(String),
+    /// The light can't take commands right now, 503.
+    Unavailable(String),
+    /// The light didn't answer in time, 504.
+    Unreachable(Box<runner::Unreachable>),
+    /// A command wasn't written in time, 504.
+    TimedOut(String),
+    /// The light reported a different state than a confirmed write asked for, 409.
+    Mismatch(Box<LightInfo>),
+    /// Something with the same name already exists, 409.
+    Conflict(String),
+    /// Saving or loading something the hub keeps failed, 500.
+    Storage(String),
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+impl ApiError {
+    fn status(&self) -> Status {
+        match self {
+            ApiError::BadRequest(_) => { Status::BadRequest }
+            ApiError::NotFound(_) => { Status::NotFound }
+            ApiError::Unavailable(_) => { Status::ServiceUnavailable }
+            ApiError::Unreachable(_) | ApiError::TimedOut(_) => { Status::GatewayTimeout }
+            ApiError::Mismatch(_) | ApiError::Conflict(_) => { Status::Conflict }
+            ApiError::Storage(_) => { Status::InternalServerError }
+        }
+    }
+
+    fn unknown_light(id: &str) -> Self {
+        ApiError::NotFound(format!("No light matches {:?}", id))
+    }
+
+    fn unknown_scene(name: &str) -> Self {
+        ApiError::NotFound(format!("No scene is named {:?}", name))
+    }
+
+    fn unknown_group(name: &str) -> Self {
+        ApiError::NotFound(format!("No group is named {:?}", name))
+    }
+
+    fn unknown_schedule(name: &str) -> Self {
+        ApiError::NotFound(format!("No schedule is named {:?}", name))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(message) |
+            ApiError::NotFound(message) |
+            ApiError::Unavailable(message) |
+            ApiError::TimedOut(message) |
+            ApiError::Conflict(message) |
+            ApiError::Storage(message) => { write!(f, "{}", message) }
+            ApiError::Unreachable(unreachable) => { write!(f, "{}", unreachable) }
+            ApiError::Mismatch(_) => { write!(f, "The light didn't take the change") }
+        }
+    }
+}
+
+impl From<DropReason> for ApiError {
+    fn from(reason: DropReason) -> Self {
+        ApiError::Unavailable(reason.to_string())
+    }
+}
+
+impl From<scenes::ActivationError> for ApiError {
+    fn from(error: scenes::ActivationError) -> Self {
+        match error {
+            scenes::ActivationError::UnknownLight(address) => { ApiError::unknown_light(&address) }
+            scenes::ActivationError::Dropped(reason) => { reason.into() }
+        }
+    }
+}
+
+impl From<runner::Unreachable> for ApiError {
+    fn from(unreachable: runner::Unreachable) -> Self {
+        ApiError::Unreachable(Box::new(unreachable))
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(error: StoreError) -> Self {
+        eprintln!("{}", error);
+        ApiError::Storage(error.to_string())
+    }
+}
+
+impl From<json::Error<'_>> for ApiError {
+    fn from(error: json::Error<'_>) -> Self {
+        ApiError::BadRequest(format!("Invalid request body: {}", error))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        let error = self.to_string();
+        let (last_known, reported) = match self {
+            ApiError::Unreachable(unreachable) => {
+                (unreachable.last_known.map(|(state, age)| LastKnownResponse { state, age_ms: age.as_millis() as u64 }), None)
+            }
+            ApiError::Mismatch(reported) => { (None, Some(*reported)) }
+            _ => { (None, None) }
+        };
+
+        (status, Json(ErrorResponse { error, last_known, reported })).respond_to(request)
+    }
+}
+
+// MARK: Routes
+
+/// Every route of the JSON API, mounted under /api/v1.
+pub(crate) fn routes() -> Vec<Route> {
+    routes![
+        list_lights,
+        get_light,
+        set_power,
+        set_color,
+        set_name,
+        start_transition,
+        cancel_transition,
+        events,
+        control_channel,
+        list_scenes,
+        get_scene,
+        create_scene,
+        update_scene,
+        delete_scene,
+        activate_scene,
+        list_groups,
+        get_group,
+        put_group,
+        delete_group,
+        set_group_power,
+        set_group_color,
+        start_group_transition,
+        list_schedules,
+        get_schedule,
+        put_schedule,
+        delete_schedule
+    ]
+}
+
+/// Keeps errors under /api/v1 in JSON.
+pub(crate) fn catchers() -> Vec<Catcher> {
+    catchers![not_found, default_catcher]
+}
+
+#[get("/lights")]
+pub(crate) fn list_lights(state: &State<PeripheralState>) -> Json<Vec<LightResponse>> {
+    Json(state.lights().iter().map(LightResponse::new).collect())
+}
+
+/// Serves the cached state while it's fresh, `refresh=true` always asks the light.
+#[get("/lights/<id>?<refresh>")]
+pub(crate) async fn get_light(id: &str, refresh: Option<bool>, state: &State<PeripheralState>) -> ApiResult<LightResponse> {
+    let light = state.find(id).ok_or_else(|| ApiError::unknown_light(id))?;
+    runner::get_device_info(&light, refresh.unwrap_or(false)).await?;
+
+    Ok(Json(LightResponse::new(&light)))
+}
+
+/// Writes answer once the command is written, or with `confirm=true` once the light reports the
+/// new state.
+#[put("/lights/<id>/power?<confirm>", data = "<update>")]
+pub(crate) async fn set_power(id: &str, confirm: Option<bool>, update: Result<Json<PowerUpdate>, json::Error<'_>>, state: &State<PeripheralState>) -> ApiResult<LightResponse> {
+    let light = state.find(id).ok_or_else(|| ApiError::unknown_light(id))?;
+    let update = update?;
+
+    let outcome = wait_for_command(&light, light.set_power(update.on)?).await?;
+    let confirmed = confirm_write(&light, confirm, outcome, |light_info| light_info.is_on == update.on).await?;
+
+    Ok(Json(LightResponse::after_command(&light, outcome, confirmed)))
+}
+
+#[put("/lights/<id>/color?<confirm>", data = "<update>")]
+pub(crate) async fn set_color(id: &str, confirm: Option<bool>, update: Result<Json<ColorUpdate>, json::Error<'_>>, state: &State<PeripheralState>) -> ApiResult<LightResponse> {
+    let light = state.find(id).ok_or_else(|| ApiError::unknown_light(id))?;
+    let update = update?;
+    update.check()?;
+
+    let color = update.apply_to(&light).await?;
+    let outcome = wait_for_command(&light, light.set_color(color.clone())?).await?;
+    // Colors are sent as bytes, so compare what survives the trip.
+    let confirmed = confirm_write(&light, confirm, outcome, |light_info| light_info.color.to_raw_data() == color.to_raw_data()).await?;
+
+    Ok(Json(LightResponse::after_command(&light, outcome, confirmed)))
+}
+
+#[put("/lights/<id>/name?<confirm>", data = "<update>")]
+pub(crate) async fn set_name(id: &str, confirm: Option<bool>, update: Result<Json<NameUpdate>, json::Error<'_>>, state: &State<PeripheralState>) -> ApiResult<LightResponse> {
+    let light = state.find(id).ok_or_else(|| ApiError::unknown_light(id))?;
+    let update = update?;
+    // The light reports its name back with the rest of its state in a single frame.
+    if update.name.is_empty() || update.name.len() > MAX_NAME_LENGTH {
+        return Err(ApiError::BadRequest(format!("name must be 1 to {} bytes long", MAX_NAME_LENGTH)));
+    }
+
+    let ticket = light.send(peripheral::Command::SetName(update.name.clone()))?;
+    // The light only reports its new name with the next device info. A confirmed write asks for
+    // that itself, otherwise it's asked for in the background so the cache catches up.
+    if !confirm.unwrap_or(false) {
+        let light = light.clone();
+        let written_at = Instant::now();
+        rocket::tokio::spawn(async move { runner::request_device_info(&light, Some(written_at)).await });
+    }
+    let outcome = wait_for_command(&light, ticket).await?;
+    let confirmed = confirm_write(&light, confirm, outcome, |light_info| light_info.name == update.name).await?;
+
+    Ok(Json(LightResponse::after_command(&light, outcome, confirmed)))
+}
+
+/// Starts fading the light to a color and answers right away, the fade goes on in the background
+/// until it's done or another color or brightness change cancels it.
+#[put("/lights/<id>/transition", data = "<update>")]
+pub(crate) async fn start_transition(id: &str, update: Result<Json<TransitionUpdate>, json::Error<'_>>, state: &State<PeripheralState>) -> ApiResult<LightResponse> {
+    let light = state.find(id).ok_or_else(|| ApiError::unknown_light(id))?;
+    let update = update?;
+    update.check()?;
+
+    update.start(&light).await?;
+
+    Ok(Json(LightResponse::new(&light)))
+}
+
+/// Stops a running transition where it is.
+#[delete("/lights/<id>/transition")]
+pub(crate) fn cancel_transition(id: &str, state: &State<PeripheralState>) -> ApiResult<LightResponse> {
+    let light = state.find(id).ok_or_else(|| ApiError::unknown_light(id))?;
+    if !light.cancel_transition() {
+        return Err(ApiError::NotFound(format!("{} isn't transitioning", light.address())));
+    }
+
+    Ok(Json(LightResponse::new(&light)))
+}
+
+/// Pushes a `state` event whenever a light's state changes and a `connection` event when it
+/// connects or disconnects, for every light or only the one matching `light`. Each matching
+/// light's current state is sent first.
+#[get("/events?<light>")]
+pub(crate) fn events(light: Option<&str>, state: &State<PeripheralState>, mut shutdown: Shutdown) -> Result<EventStream![], ApiError> {
+    let address = match light {
+        Some(id) => { Some(state.find(id).ok_or_else(|| ApiError::unknown_light(id))?.address()) }
+        None => { None }
+    };
+    // Subscribe before taking the snapshot so no change falls in between.
+    let mut events = state.events().subscribe();
+    let current_events: Vec<LightEvent> = state.lights().iter()
+        .filter(|light| address.as_ref().map(|address| *address == light.address()).unwrap_or(true))
+        .filter_map(|light| {
+            light.cached_light_info().map(|(light_info, _)| LightEvent::State { address: light.address(), state: light_info })
+        })
+        .collect();
+
+    Ok(EventStream! {
+        for event in current_events {
+            yield sse_event(&event);
+        }
+        loop {
+            let event = select! {
+                event = events.recv() => { event }
+                _ = &mut shutdown => { break }
+            };
+            match event {
+                Ok(event) => {
+                    if address.as_ref().map(|address| address == event.address()).unwrap_or(true) {
+                        yield sse_event(&event);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Event stream listener fell behind, dropped {} events", skipped);
+                }
+                Err(RecvError::Closed) => { break }
+            }
+        }
+    })
+}
+
+/// Upgrades to a WebSocket for streaming changes to a light, see `control::run`.
+#[get("/lights/<id>/control")]
+pub(crate) fn control_channel(id: &str, socket: WebSocket, state: &State<PeripheralState>, shutdown: Shutdown) -> Result<Channel, ApiError> {
+    let light = state.find(id).ok_or_else(|| ApiError::unknown_light(id))?;
+    let events = state.events().subscribe();
+
+    Ok(socket.channel(move |socket| crate::control::run(light, socket, events, shutdown)))
+}
+
+#[get("/scenes")]
+pub(crate) fn list_scenes(scenes: &State<SceneStore>) -> Json<Vec<Scene>> {
+    Json(scenes.list())
+}
+
+#[get("/scenes/<name>")]
+pub(crate) fn get_scene(name: &str, scenes: &State<SceneStore>) -> ApiResult<Scene> {
+    scenes.get(name).map(Json).ok_or_else(|| ApiError::unknown_scene(name))
+}
+
+#[post("/scenes", data = "<scene>")]
+pub(crate) async fn create_scene(scene: Result<Json<SceneCreate>, json::Error<'_>>, state: &State<PeripheralState>, scenes: &State<SceneStore>) -> ApiResult<Scene> {
+    let scene = scene?;
+    if scene.name.is_empty() {
+        return Err(ApiError::BadRequest(String::from("name must not be empty")));
+    }
+    if scenes.get(&scene.name).is_some() {
+        return Err(ApiError::Conflict(format!("A scene is already named {:?}, update it instead", scene.name)));
+    }
+
+    let lights = find_lights(&scene.lights, state)?;
+    let scene = Scene { name: scene.name.clone(), lights: scenes::snapshot(&lights).await? };
+    // Checked again when it's added, another request might have taken the name while the lights
+    // were asked for their state.
+    if !scenes.insert_new(scene.clone())? {
+        return Err(ApiError::Conflict(format!("A scene is already named {:?}, update it instead", scene.name)));
+    }
+
+    Ok(Json(scene))
+}
+
+#[put("/scenes/<name>", data = "<update>")]
+pub(crate) async fn update_scene(name: &str, update: Result<Json<SceneUpdate>, json::Error<'_>>, state: &State<PeripheralState>, scenes: &State<SceneStore>) -> ApiResult<Scene> {
+    let update = update?;
+    let scene = scenes.get(name).ok_or_else(|| ApiError::unknown_scene(name))?;
+    let ids = update.lights.clone()
+        .unwrap_or_else(|| scene.lights.iter().map(|scene_light| scene_light.address.clone()).collect());
+
+    let lights = find_lights(&ids, state)?;
+    let scene = Scene { name: scene.name, lights: scenes::snapshot(&lights).await? };
+    scenes.insert(scene.clone())?;
+
+    Ok(Json(scene))
+}
+
+#[delete("/scenes/<name>")]
+pub(crate) fn delete_scene(name: &str, scenes: &State<SceneStore>) -> ApiResult<Scene> {
+    scenes.remove(name)?.map(Json).ok_or_else(|| ApiError::unknown_scene(name))
+}
+
+/// Brings every light in the scene to its saved state at once. Lights that are gone or can't
+/// take commands are reported individually, the others are still changed.
+#[post("/scenes/<name>/activate")]
+pub(crate) async fn activate_scene(name: &str, state: &State<PeripheralState>, scenes: &State<SceneStore>) -> ApiResult<SceneActivationResponse> {
+    let scene = scenes.get(name).ok_or_else(|| ApiError::unknown_scene(name))?;
+    println!("Activating scene {:?}", scene.name);
+
+    let lights = join_all(scene.lights.iter().map(|scene_light| async move {
+        let (command, error) = match activate_scene_light(scene_light, state).await {
+            Ok(outcome) => { (Some(outcome), None) }
+            Err(err) => { (None, Some(err.to_string())) }
+        };
+        LightCommandResponse { address: scene_light.address.clone(), command, error }
+    })).await;
+
+    Ok(Json(SceneActivationResponse { scene: scene.name, lights }))
+}
+
+#[get("/groups")]
+pub(crate) fn list_groups(state: &State<PeripheralState>, groups: &State<GroupStore>) -> Json<Vec<GroupResponse>> {
+    Json(groups.list().iter().map(|group| GroupResponse::new(group, state)).collect())
+}
+
+#[get("/groups/<name>")]
+pub(crate) fn get_group(name: &str, state: &State<PeripheralState>, groups: &State<GroupStore>) -> ApiResult<GroupResponse> {
+    let group = groups.get(name).ok_or_else(|| ApiError::unknown_group(name))?;
+
+    Ok(Json(GroupResponse::new(&group, state)))
+}
+
+/// Creates the group or replaces its members.
+#[put("/groups/<name>", data = "<update>")]
+pub(crate) fn put_group(name: &str, update: Result<Json<GroupUpdate>, json::Error<'_>>, state: &State<PeripheralState>, groups: &State<GroupStore>) -> ApiResult<GroupResponse> {
+    let update = update?;
+    let lights = find_lights(&update.lights, state)?;
+    let mut addresses: Vec<String> = Vec::new();
+    for address in lights.iter().map(|light| light.address()) {
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    let group = Group { name: String::from(name), lights: addresses };
+    groups.insert(group.clone())?;
+
+    Ok(Json(GroupResponse::new(&group, state)))
+}
+
+#[delete("/groups/<name>")]
+pub(crate) fn delete_group(name: &str, state: &State<PeripheralState>, groups: &State<GroupStore>) -> ApiResult<GroupResponse> {
+    let group = groups.remove(name)?.ok_or_else(|| ApiError::unknown_group(name))?;
+
+    Ok(Json(GroupResponse::new(&group, state)))
+}
+
+#[put("/groups/<name>/power", data = "<update>")]
+pub(crate) async fn set_group_power(name: &str, update: Result<Json<PowerUpdate>, json::Error<'_>>, state: &State<PeripheralState>, groups: &State<GroupStore>) -> ApiResult<GroupCommandResponse> {
+    let group = groups.get(name).ok_or_else(|| ApiError::unknown_group(name))?;
+    let on = update?.on;
+
+    let members = group.members(state);
+    let lights = join_all(members.iter().map(|light| command_result(light, async move {
+        wait_for_command(light, light.set_power(on)?).await
+    }))).await;
+
+    Ok(Json(GroupCommandResponse { group: group.name, state: groups::aggregate_state(&members), lights }))
+}
+
+/// Changes the given color components of every member, each keeps the components left out.
+#[put("/groups/<name>/color", data = "<update>")]
+pub(crate) async fn set_group_color(name: &str, update: Result<Json<ColorUpdate>, json::Error<'_>>, state: &State<PeripheralState>, groups: &State<GroupStore>) -> ApiResult<GroupCommandResponse> {
+    let group = groups.get(name).ok_or_else(|| ApiError::unknown_group(name))?;
+    let update = update?;
+    update.check()?;
+
+    let members = group.members(state);
+    let update = &update;
+    let lights = join_all(members.iter().map(|light| command_result(light, async move {
+        let color = update.apply_to(light).await?;
+        wait_for_command(light, light.set_color(color)?).await
+    }))).await;
+
+    Ok(Json(GroupCommandResponse { group: group.name, state: groups::aggregate_state(&members), lights }))
+}
+
+/// Starts the same fade on every member, each keeps the components left out.
+#[put("/groups/<name>/transition", data = "<update>")]
+pub(crate) async fn start_group_transition(name: &str, update: Result<Json<TransitionUpdate>, json::Error<'_>>, state: &State<PeripheralState>, groups: &State<GroupStore>) -> ApiResult<GroupCommandResponse> {
+    let group = groups.get(name).ok_or_else(|| ApiError::unknown_group(name))?;
+    let update = update?;
+    update.check()?;
+
+    let members = group.members(state);
+    let update = &update;
+    // Nothing is written yet when the fade starts, members that started it count as sent.
+    let lights = join_all(members.iter().map(|light| command_result(light, async move {
+        update.start(light).await.map(|_| CommandOutcome::Sent)
+    }))).await;
+
+    Ok(Json(GroupCommandResponse { group: group.name, state: groups::aggregate_state(&members), lights }))
+}
+
+#[get("/schedules")]
+pub(crate) fn list_schedules(scheduler: &State<Scheduler>) -> Json<Vec<ScheduleResponse>> {
+    Json(scheduler.list().into_iter().map(|schedule| ScheduleResponse::new(schedule, scheduler)).collect())
+}
+
+#[get("/schedules/<name>")]
+pub(crate) fn get_schedule(name: &str, scheduler: &State<Scheduler>) -> ApiResult<ScheduleResponse> {
+    let schedule = scheduler.get(name).ok_or_else(|| ApiError::unknown_schedule(name))?;
+
+    Ok(Json(ScheduleResponse::new(schedule, scheduler)))
+}
+
+/// Creates the schedule or replaces it. Times are in the configured time zone, the hub's if none
+/// is.
+#[put("/schedules/<name>", data = "<update>")]
+pub(crate) fn put_schedule(name: &str, update: Result<Json<ScheduleUpdate>, json::Error<'_>>, state: &State<PeripheralState>, scenes: &State<SceneStore>, groups: &State<GroupStore>, scheduler: &State<Scheduler>) -> ApiResult<ScheduleResponse> {
+    let update = update?;
+    let action = update.resolve(state, scenes, groups, scheduler)?;
+    let schedule = Schedule {
+        name: String::from(name),
+        trigger: update.trigger.clone(),
+        action,
+        enabled: update.enabled,
+        last_run: scheduler.get(name).and_then(|schedule| schedule.last_run),
+    };
+    if schedule.enabled && scheduler.next_run(&schedule).is_none() {
+        return Err(ApiError::BadRequest(String::from("The schedule would never run")));
+    }
+    scheduler.insert(schedule.clone())?;
+
+    Ok(Json(ScheduleResponse::new(schedule, scheduler)))
+}
+
+#[delete("/schedules/<name>")]
+pub(crate) fn delete_schedule(name: &str, scheduler: &State<Scheduler>) -> ApiResult<ScheduleResponse> {
+    let schedule = scheduler.remove(name)?.ok_or_else(|| ApiError::unknown_schedule(name))?;
+
+    Ok(Json(ScheduleResponse::new(schedule, scheduler)))
+}
+
+#[catch(404)]
+pub(crate) fn not_found(request: &Request) -> ApiError {
+    ApiError::NotFound(format!("No route for {} {}", request.method(), request.uri()))
+}
+
+/// Keeps errors Rocket answers on its own, e.g. a failed WebSocket handshake, in JSON.
+#[catch(default)]
+pub(crate) fn default_catcher(status: Status, _: &Request) -> (Status, Json<ErrorResponse>) {
+    (status, Json(ErrorResponse { error: String::from(status.reason_lossy()), last_known: None, reported: None }))
+}
+
+// MARK: Helpers
+
+/// Waits for a queued command to be written, a superseded command counts as done since a newer
+/// one of the same kind took its place.
+async fn wait_for_command(light: &Light, ticket: CommandTicket) -> Result<CommandOutcome, ApiError> {
+    let command_timeout = light.timing().command_timeout();
+    match timeout(command_timeout, ticket.outcome()).await {
+        Ok(CommandOutcome::Dropped(reason)) => { Err(ApiError::from(reason)) }
+        Ok(outcome) => { Ok(outcome) }
+        Err(_) => {
+            Err(ApiError::TimedOut(format!("The command wasn't written to {} within {:?}, it's still queued", light.address(), command_timeout)))
+        }
+    }
+}
+
+/// For confirmed writes, asks the light for its state once the command was written and checks it
+/// with `took_effect`. The light's answer replaces the cached state, so a mismatch also undoes the
+/// optimistic update made when the command was queued.
+async fn confirm_write(light: &Light, confirm: Option<bool>, outcome: CommandOutcome, took_effect: impl Fn(&LightInfo) -> bool) -> Result<Option<bool>, ApiError> {
+    if !confirm.unwrap_or(false) {
+        return Ok(None);
+    }
+    // A newer command of the same kind took this one's place, there's nothing left to check.
+    if outcome != CommandOutcome::Sent {
+        return Ok(Some(false));
+    }
+
+    let written_at = Instant::now();
+    let reported = runner::request_device_info(light, Some(written_at)).await?;
+    if !took_effect(&reported) {
+        eprintln!("{} didn't take a confirmed write, it reported: {:?}", light.address(), reported);
+        return Err(ApiError::Mismatch(Box::new(reported)));
+    }
+
+    Ok(Some(true))
+}
+
+/// Reports how changing one of several lights went.
+async fn command_result(light: &Light, command: impl Future<Output = Result<CommandOutcome, ApiError>>) -> LightCommandResponse {
+    let (command, error) = match command.await {
+        Ok(outcome) => { (Some(outcome), None) }
+        Err(err) => { (None, Some(err.to_string())) }
+    };
+
+    LightCommandResponse { address: light.address(), command, error }
+}
+
+/// Looks up every light in `ids`, failing on the first one that doesn't match any.
+fn find_lights(ids: &[String], state: &PeripheralState) -> Result<Vec<Light>, ApiError> {
+    if ids.is_empty() {
+        return Err(ApiError::BadRequest(String::from("lights must not be empty")));
+    }
+
+    ids.iter().map(|id| state.find(id).ok_or_else(|| ApiError::unknown_light(id))).collect()
+}
+
+/// Waits for every command that brings a light to its scene state, reporting the last outcome.
+async fn activate_scene_light(scene_light: &SceneLight, state: &PeripheralState) -> Result<CommandOutcome, ApiError> {
+    let (light, tickets) = scenes::activate_light(scene_light, state)?;
+    let mut outcome = CommandOutcome::Sent;
+    for ticket in tickets {
+        outcome = wait_for_command(&light, ticket).await?;
+    }
+
+    Ok(outcome)
+}
+
+fn sse_event(event: &LightEvent) -> Event {
+    let name = match event {
+        LightEvent::State { .. } => { "state" }
+        LightEvent::Connection { .. } => { "connection" }
+    };
+
+    Event::json(event).event(name)
+}
+
+fn check_range(field: &str, value: Option<f64>, max: f64) -> Result<(), ApiError> {
+    match value {
+        Some(value) if !(0.0..=max).contains(&value) => {
+            Err(ApiError::BadRequest(format!("{} must be between 0 and {}", field, max)))
+        }
+        _ => { Ok(()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use rocket::config::LogLevel;
+    use rocket::http::ContentType;
+    use rocket::local::asynchronous::{Client, LocalResponse};
+    use rocket::serde::json::Value;
+    use tokio::sync::broadcast;
+
+    use crate::config::TimingConfig;
+    use crate::runner::testing::{silent_light, started_light};
+    use crate::schedules::Clock;
+    use crate::simulator::{SimulatedLight, SimulatedTransport};
+    use crate::solar::Location;
+
+    fn color(h: f64, s: f64, v: f64) -> HSVColor {
+        HSVColor { h, s, v }
+    }
+
+    /// A directory for the stores of one test, it's up to the test to remove it.
+    fn temporary_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("home-light-hub-api-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// Serves the API for `state`, keeping what's created in `directory`.
+    async fn client(state: &PeripheralState, directory: &Path) -> Client {
+        let path = |name: &str| String::from(directory.join(name).to_str().unwrap());
+        let clock = Clock { time_zone: Some(chrono_tz::Europe::Berlin), location: Some(Location { latitude: 52.52, longitude: 13.405 }) };
+        let config = rocket::Config { log_level: LogLevel::Off, ..rocket::Config::debug_default() };
+        let rocket = rocket::custom(config)
+            .manage(state.clone())
+            .manage(SceneStore::load(&path("scenes.json")).unwrap())
+            .manage(GroupStore::load(&path("groups.json")).unwrap())
+            .manage(Scheduler::load(&path("schedules.json"), clock).unwrap())
+            .mount("/api/v1", routes())
+            .register("/api/v1", catchers());
+
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    async fn simulated_lights(count: usize) -> PeripheralState {
+        let state = PeripheralState::new();
+        for index in 0..count {
+            state.add_light(started_light(index, &TimingConfig::default(), state.events()).await);
+        }
+
+        state
+    }
+
+    async fn json(response: LocalResponse<'_>, status: Status) -> Value {
+        assert_eq!(response.status(), status);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        response.into_json().await.unwrap()
+    }
+
+    async fn put<'c>(client: &'c Client, uri: &str, body: &str) -> LocalResponse<'c> {
+        client.put(String::from(uri)).header(ContentType::JSON).body(body).dispatch().await
+    }
+
+    #[tokio::test]
+    async fn lights_are_listed_and_changed() {
+        let directory = temporary_directory("lights");
+        let client = client(&simulated_lights(2).await, &directory).await;
+
+        let lights = json(client.get("/api/v1/lights").dispatch().await, Status::Ok).await;
+        assert_eq!(lights.as_array().unwrap().len(), 2);
+        assert_eq!(lights[0]["name"], "Simulated Light 0");
+
+        let light = json(put(&client, "/api/v1/lights/simulated-0/power", r#"{"on": false}"#).await, Status::Ok).await;
+        assert_eq!(light["command"], "sent");
+        assert_eq!(light["state"]["is_on"], false);
+        assert!(light.get("confirmed").is_none());
+
+        let light = json(put(&client, "/api/v1/lights/Simulated%20Light%201/color", r#"{"h": 240}"#).await, Status::Ok).await;
+        assert_eq!(light["address"], "simulated-1");
+        assert_eq!(light["state"]["color"]["h"], 240.0);
+
+        let error = json(put(&client, "/api/v1/lights/simulated-0/color", r#"{"h": 400}"#).await, Status::BadRequest).await;
+        assert_eq!(error["error"], "h must be between 0 and 360");
+        let error = json(put(&client, "/api/v1/lights/simulated-0/power", "on").await, Status::BadRequest).await;
+        assert!(error["error"].as_str().unwrap().starts_with("Invalid request body"), "{}", error);
+        let error = json(client.get("/api/v1/lights/nothing").dispatch().await, Status::NotFound).await;
+        assert_eq!(error["error"], r#"No light matches "nothing""#);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn confirmed_writes_answer_once_the_light_reports() {
+        let directory = temporary_directory("confirmed");
+        let client = client(&simulated_lights(1).await, &directory).await;
+
+        let light = json(put(&client, "/api/v1/lights/simulated-0/power?confirm=true", r#"{"on": false}"#).await, Status::Ok).await;
+        assert_eq!(light["command"], "sent");
+        assert_eq!(light["confirmed"], true);
+        assert_eq!(light["state"]["is_on"], false);
+
+        let light = json(put(&client, "/api/v1/lights/simulated-0/name?confirm=true", r#"{"name": "Porch"}"#).await, Status::Ok).await;
+        assert_eq!(light["confirmed"], true);
+        assert_eq!(light["name"], "Porch");
+
+        let body = format!(r#"{{"name": "{}"}}"#, "n".repeat(MAX_NAME_LENGTH + 1));
+        let error = json(put(&client, "/api/v1/lights/simulated-0/name", &body).await, Status::BadRequest).await;
+        assert_eq!(error["error"], format!("name must be 1 to {} bytes long", MAX_NAME_LENGTH));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_stopped_light_cant_take_commands() {
+        let directory = temporary_directory("stopped");
+        let state = simulated_lights(1).await;
+        let client = client(&state, &directory).await;
+        state.find("simulated-0").unwrap().stop(Duration::from_secs(2)).await;
+
+        json(put(&client, "/api/v1/lights/simulated-0/power", r#"{"on": false}"#).await, Status::ServiceUnavailable).await;
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_silent_light_is_served_from_the_cache_until_a_refresh() {
+        let directory = temporary_directory("silent");
+        let timing = TimingConfig { device_info_timeout_ms: 50, device_info_retries: 0, command_timeout_ms: 50, ..TimingConfig::default() };
+        let last_known = LightInfo { name: String::from("Silent Light"), is_on: true, color: color(0.0, 0.0, 1.0), animation: None, schedule: Vec::new() };
+        let (light, _command_rx) = silent_light("silent", &timing, last_known);
+        let state = PeripheralState::new();
+        state.add_light(light);
+        let client = client(&state, &directory).await;
+
+        let light = json(client.get("/api/v1/lights/silent").dispatch().await, Status::Ok).await;
+        assert_eq!(light["state"]["name"], "Silent Light");
+
+        let error = json(client.get("/api/v1/lights/silent?refresh=true").dispatch().await, Status::GatewayTimeout).await;
+        assert_eq!(error["last_known"]["state"]["name"], "Silent Light");
+        assert!(error["last_known"]["age_ms"].as_u64().unwrap() >= 1_000);
+
+        // The command is never written.
+        let error = json(put(&client, "/api/v1/lights/silent/power", r#"{"on": false}"#).await, Status::GatewayTimeout).await;
+        assert!(error["error"].as_str().unwrap().contains("still queued"), "{}", error);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn scenes_are_created_once_and_activated() {
+        let directory = temporary_directory("scenes");
+        let client = client(&simulated_lights(1).await, &directory).await;
+
+        let body = r#"{"name": "Evening", "lights": ["simulated-0"]}"#;
+        let scene = json(client.post("/api/v1/scenes").header(ContentType::JSON).body(body).dispatch().await, Status::Ok).await;
+        assert_eq!(scene["lights"][0]["address"], "simulated-0");
+        json(client.post("/api/v1/scenes").header(ContentType::JSON).body(body).dispatch().await, Status::Conflict).await;
+
+        let activation = json(client.post("/api/v1/scenes/Evening/activate").dispatch().await, Status::Ok).await;
+        assert_eq!(activation["lights"][0]["command"], "sent");
+        json(client.post("/api/v1/scenes/Morning/activate").dispatch().await, Status::NotFound).await;
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failing_to_save_is_a_server_error() {
+        // The stores' directory doesn't exist, so nothing can be saved.
+        let directory = std::env::temp_dir().join(format!("home-light-hub-api-missing-{}", std::process::id()));
+        let client = client(&simulated_lights(1).await, &directory).await;
+
+        let body = r#"{"name": "Evening", "lights": ["simulated-0"]}"#;
+        let error = json(client.post("/api/v1/scenes").header(ContentType::JSON).body(body).dispatch().await, Status::InternalServerError).await;
+        assert!(error["error"].as_str().unwrap().starts_with("Couldn't access"), "{}", error);
+        json(client.get("/api/v1/scenes/Evening").dispatch().await, Status::NotFound).await;
+    }
+
+    #[tokio::test]
+    async fn groups_are_changed_together() {
+        let directory = temporary_directory("groups");
+        let client = client(&simulated_lights(2).await, &directory).await;
+
+        let group = json(put(&client, "/api/v1/groups/Downstairs", r#"{"lights": ["simulated-0", "simulated-1", "simulated-0"]}"#).await, Status::Ok).await;
+        assert_eq!(group["lights"].as_array().unwrap().len(), 2);
+
+        let changed = json(put(&client, "/api/v1/groups/Downstairs/power", r#"{"on": false}"#).await, Status::Ok).await;
+        for light in changed["lights"].as_array().unwrap() {
+            assert_eq!(light["command"], "sent");
+        }
+        assert_eq!(changed["state"]["is_on"], false);
+
+        json(put(&client, "/api/v1/groups/Upstairs/power", r#"{"on": false}"#).await, Status::NotFound).await;
+        json(put(&client, "/api/v1/groups/Upstairs", r#"{"lights": []}"#).await, Status::BadRequest).await;
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn transitions_are_started_and_cancelled() {
+        let directory = temporary_directory("transitions");
+        let client = client(&simulated_lights(1).await, &directory).await;
+
+        let light = json(put(&client, "/api/v1/lights/simulated-0/transition", r#"{"h": 120, "duration_ms": 60000}"#).await, Status::Ok).await;
+        assert_eq!(light["transitioning"], true);
+        let light = json(client.delete("/api/v1/lights/simulated-0/transition").dispatch().await, Status::Ok).await;
+        assert_eq!(light["transitioning"], false);
+        json(client.delete("/api/v1/lights/simulated-0/transition").dispatch().await, Status::NotFound).await;
+
+        json(put(&client, "/api/v1/lights/simulated-0/transition", r#"{"h": 120, "duration_ms": 0}"#).await, Status::BadRequest).await;
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn schedules_are_checked_and_saved() {
+        let directory = temporary_directory("schedules");
+        let client = client(&simulated_lights(1).await, &directory).await;
+
+        let body = r#"{"trigger": {"type": "cron", "expression": "30 7 * * *"}, "action": {"type": "power", "target": {"lights": ["simulated-0"]}, "on": true}}"#;
+        let schedule = json(put(&client, "/api/v1/schedules/Morning", body).await, Status::Ok).await;
+        assert_eq!(schedule["enabled"], true);
+        assert!(schedule["next_run"].is_string(), "{}", schedule);
+        json(client.get("/api/v1/schedules/Morning").dispatch().await, Status::Ok).await;
+        let body = r#"{"trigger": {"type": "cron", "expression": "30 7 * *"}, "action": {"type": "power", "target": {"lights": ["simulated-0"]}, "on": true}}"#;
+        json(put(&client, "/api/v1/schedules/Broken", body).await, Status::BadRequest).await;
+
+        json(client.delete("/api/v1/schedules/Morning").dispatch().await, Status::Ok).await;
+        json(client.get("/api/v1/schedules/Morning").dispatch().await, Status::NotFound).await;
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn solar_offsets_out_of_range_are_rejected() {
+        let directory = temporary_directory("solar");
+        let client = client(&simulated_lights(1).await, &directory).await;
+
+        let body = r#"{"trigger": {"type": "solar", "event": "sunset", "offset_minutes": -30}, "action": {"type": "power", "target": {"lights": ["simulated-0"]}, "on": true}}"#;
+        let schedule = json(put(&client, "/api/v1/schedules/Evening", body).await, Status::Ok).await;
+        assert!(schedule["next_run"].is_string(), "{}", schedule);
+
+        let body = format!(r#"{{"trigger": {{"type": "solar", "event": "sunset", "offset_minutes": {}}}, "action": {{"type": "power", "target": {{"lights": ["simulated-0"]}}, "on": true}}}}"#, i64::MIN);
+        let error = json(put(&client, "/api/v1/schedules/Evening", &body).await, Status::BadRequest).await;
+        assert_eq!(error["error"], "offset_minutes must be between -720 and 720");
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn errors_rocket_answers_itself_are_json() {
+        let directory = temporary_directory("catchers");
+        let client = client(&simulated_lights(1).await, &directory).await;
+
+        let error = json(client.get("/api/v1/nothing").dispatch().await, Status::NotFound).await;
+        assert_eq!(error["error"], "No route for GET /api/v1/nothing");
+        // Not a WebSocket handshake.
+        let error = json(client.get("/api/v1/lights/simulated-0/control").dispatch().await, Status::UpgradeRequired).await;
+        assert_eq!(error["error"], "Upgrade Required");
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_confirmed_write_checks_what_the_light_reports() {
+        // Not waiting for the first report, its answer mustn't be taken for the confirmation.
+        let transport = SimulatedTransport::new("simulated-0", SimulatedLight::new("Simulated Light 0"));
+        let light = runner::start(transport, &TimingConfig::default(), broadcast::channel(16).0);
+        let target = color(240.0, 1.0, 1.0);
+
+        let outcome = wait_for_command(&light, light.set_color(target.clone()).unwrap()).await.unwrap();
+        let confirmed = confirm_write(&light, Some(true), outcome, |light_info| light_info.color.to_raw_data() == target.to_raw_data()).await.unwrap();
+        assert_eq!(confirmed, Some(true));
+        assert_eq!(confirm_write(&light, None, outcome, |_| false).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn a_write_the_light_didnt_take_is_a_conflict() {
+        let light = started_light(0, &TimingConfig::default(), broadcast::channel(16).0).await;
+        let outcome = wait_for_command(&light, light.set_color(color(240.0, 1.0, 1.0)).unwrap()).await.unwrap();
+
+        // Checking for a color the light wasn't set to stands in for a light that ignored the write.
+        let expected = color(120.0, 1.0, 1.0).to_raw_data();
+        match confirm_write(&light, Some(true), outcome, |light_info| light_info.color.to_raw_data() == expected).await {
+            Err(error @ ApiError::Mismatch(_)) => {
+                assert_eq!(error.status(), Status::Conflict);
+                if let ApiError::Mismatch(reported) = error {
+                    assert_eq!(reported.color.to_raw_data(), color(240.0, 1.0, 1.0).to_raw_data());
+                }
+            }
+            other => { panic!("Expected a mismatch, got {:?}", other) }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_superseded_write_isnt_confirmed() {
+        let light = started_light(0, &TimingConfig::default(), broadcast::channel(16).0).await;
+        let confirmed = confirm_write(&light, Some(true), CommandOutcome::Superseded, |_| true).await.unwrap();
+        assert_eq!(confirmed, Some(false));
+    }
+}
@@ -0,0 +1,510 @@
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use rocket::figment::Figment;
+use rocket::figment::providers::{Env, Format, Serialized, Toml};
+use rocket::serde::json::{self, Value};
+use btleplug::api::bleuuid::uuid_from_u16;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::solar::Location;
+use crate::transport::serial;
+
+/// The Bluno's serial characteristic, which is what the firmware talks over.
+const DEFAULT_NOTIFY_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xDFB1);
+/// Config file read when no other path is given.
+const DEFAULT_CONFIG_PATH: &str = "home-light-hub.toml";
+/// Environment variable holding the path of the config file.
+const CONFIG_PATH_ENV: &str = "HOME_LIGHT_CONFIG";
+/// Prefix of environment variables overriding config values, nested keys are separated by a
+/// double underscore, e.g. `HOME_LIGHT_SERVER__PORT=9000`.
+const ENV_PREFIX: &str = "HOME_LIGHT_";
+
+/// Path of the config file to read.
+const CONFIG_FLAG: &str = "--config";
+/// Port the HTTP API listens on.
+const PORT_FLAG: &str = "--port";
+/// Runs the hub against this many in-process simulated lights instead of scanning for real ones.
+const SIMULATED_LIGHTS_FLAG: &str = "--simulated-lights";
+/// Path of a serial port with a light attached, may be given more than once.
+const SERIAL_PORT_FLAG: &str = "--serial-port";
+
+/// Everything that can be tweaked without recompiling. Values are read, in increasing order of
+/// precedence, from the defaults, the config file, `HOME_LIGHT_*` environment variables and the
+/// command line.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub server: ServerConfig,
+    pub bluetooth: BluetoothConfig,
+    pub serial: SerialConfig,
+    pub simulator: SimulatorConfig,
+    pub timing: TimingConfig,
+    pub storage: StorageConfig,
+    pub location: LocationConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BluetoothConfig {
+    /// Only devices whose name contains one of these strings will be tried.
+    pub name_filters: Vec<String>,
+    /// UUID of the characteristic for which we should subscribe to notifications.
+    pub notify_characteristic_uuid: String,
+    /// How long to wait for the Bluetooth stack to connect to a light before giving up.
+    pub connect_timeout_ms: u64,
+    /// Command run when connecting through the Bluetooth stack fails, before trying once more.
+    /// `{address}` is replaced with the light's address, e.g.
+    /// `["sudo", "hcitool", "lecc", "{address}"]`. Nothing is run if unset.
+    pub connect_fallback_command: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SerialConfig {
+    pub ports: Vec<String>,
+    pub baud_rate: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SimulatorConfig {
+    /// When set, lights on other transports are used instead of scanning over Bluetooth.
+    pub lights: Option<usize>,
+}
+
+/// Where the hub is, for schedules. Both coordinates are needed for schedules at sunrise, sunset
+/// or twilight.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LocationConfig {
+    /// Degrees, north is positive.
+    pub latitude: Option<f64>,
+    /// Degrees, east is positive.
+    pub longitude: Option<f64>,
+    /// IANA name of the time zone schedules are in, e.g. "Europe/Berlin". The system's time zone
+    /// if unset.
+    pub time_zone: Option<String>,
+}
+
+/// Where the hub keeps what's created through the API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct StorageConfig {
+    pub scenes_path: String,
+    pub groups_path: String,
+    pub schedules_path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TimingConfig {
+    /// First delay between reconnection attempts, doubled after every failure.
+    pub reconnect_backoff_min_ms: u64,
+    pub reconnect_backoff_max_ms: u64,
+    /// Pause between commands written to the same light.
+    pub command_interval_ms: u64,
+    /// How long cached light info is served before asking the light again, forever if unset.
+    pub light_info_ttl_ms: Option<u64>,
+    /// How long to wait for the light to answer a request for its state before asking again.
+    pub device_info_timeout_ms: u64,
+    /// How many more times to ask before reporting the light as unreachable.
+    pub device_info_retries: u32,
+    /// How many more times a failed write is tried before the command is dropped.
+    pub command_retries: u32,
+    /// Pause before a failed write is tried again, giving the reconnect it asks for time to start.
+    pub command_retry_delay_ms: u64,
+    /// How long an API request waits for its command to be written before giving up on it.
+    pub command_timeout_ms: u64,
+    /// How often a light's connection is checked on while it's supposed to be up.
+    pub health_check_interval_ms: u64,
+    /// How long each light gets to write what's queued and disconnect when the hub shuts down.
+    pub shutdown_timeout_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { port: 8000 }
+    }
+}
+
+impl Default for BluetoothConfig {
+    fn default() -> Self {
+        BluetoothConfig {
+            name_filters: vec![String::from("TEST_DEVICE"), String::from("Bluno")],
+            notify_characteristic_uuid: DEFAULT_NOTIFY_CHARACTERISTIC_UUID.to_string(),
+            connect_timeout_ms: 10_000,
+            connect_fallback_command: None,
+        }
+    }
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig { ports: Vec::new(), baud_rate: serial::DEFAULT_BAUD_RATE }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            scenes_path: String::from("scenes.json"),
+            groups_path: String::from("groups.json"),
+            schedules_path: String::from("schedules.json"),
+        }
+    }
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        TimingConfig {
+            reconnect_backoff_min_ms: 100,
+            reconnect_backoff_max_ms: 5_000,
+            command_interval_ms: 100,
+            light_info_ttl_ms: None,
+            device_info_timeout_ms: 2_000,
+            device_info_retries: 1,
+            command_retries: 3,
+            command_retry_delay_ms: 500,
+            command_timeout_ms: 5_000,
+            health_check_interval_ms: 1_000,
+            shutdown_timeout_ms: 5_000,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config for this run from the config file, the environment and `args`, which
+    /// should not include the program name.
+    pub fn load(args: &[String]) -> Result<Self, ConfigError> {
+        let explicit_path = flag_values(args, CONFIG_FLAG)?.pop()
+            .or_else(|| std::env::var(CONFIG_PATH_ENV).ok());
+        let path = explicit_path.clone().unwrap_or_else(|| String::from(DEFAULT_CONFIG_PATH));
+        if explicit_path.is_some() && !Path::new(&path).exists() {
+            return Err(ConfigError::Invalid(format!("Config file {:?} doesn't exist", path)));
+        }
+
+        let mut figment = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::file(&path))
+            .merge(env_overrides());
+
+        if let Some(port) = flag_values(args, PORT_FLAG)?.pop() {
+            let port = port.parse::<u16>()
+                .map_err(|err| ConfigError::Invalid(format!("{} {:?}: {}", PORT_FLAG, port, err)))?;
+            figment = figment.merge(Serialized::default("server.port", port));
+        }
+        if let Some(count) = flag_values(args, SIMULATED_LIGHTS_FLAG)?.pop() {
+            let count = count.parse::<usize>()
+                .map_err(|err| ConfigError::Invalid(format!("{} {:?}: {}", SIMULATED_LIGHTS_FLAG, count, err)))?;
+            figment = figment.merge(Serialized::default("simulator.lights", count));
+        }
+        let serial_ports = flag_values(args, SERIAL_PORT_FLAG)?;
+        if !serial_ports.is_empty() {
+            figment = figment.merge(Serialized::default("serial.ports", serial_ports));
+        }
+
+        let config: Config = figment.extract().map_err(|err| ConfigError::Load(Box::new(err)))?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(String::from(message)));
+
+        if self.server.port == 0 {
+            return invalid("server.port must not be 0");
+        }
+        if self.bluetooth.name_filters.is_empty() || self.bluetooth.name_filters.iter().any(|filter| filter.is_empty()) {
+            return invalid("bluetooth.name_filters must contain at least one filter and no empty filters");
+        }
+        if let Err(err) = Uuid::parse_str(&self.bluetooth.notify_characteristic_uuid) {
+            return Err(ConfigError::Invalid(format!("bluetooth.notify_characteristic_uuid is not a valid UUID: {}", err)));
+        }
+        if self.bluetooth.connect_timeout_ms == 0 {
+            return invalid("bluetooth.connect_timeout_ms must not be 0");
+        }
+        if self.bluetooth.connect_fallback_command.as_ref().map(|command| command.is_empty()).unwrap_or(false) {
+            return invalid("bluetooth.connect_fallback_command must not be empty, leave it unset to run nothing");
+        }
+        if self.serial.baud_rate == 0 {
+            return invalid("serial.baud_rate must not be 0");
+        }
+        if self.storage.scenes_path.is_empty() {
+            return invalid("storage.scenes_path must not be empty");
+        }
+        if self.storage.groups_path.is_empty() {
+            return invalid("storage.groups_path must not be empty");
+        }
+        if self.storage.schedules_path.is_empty() {
+            return invalid("storage.schedules_path must not be empty");
+        }
+        if self.location.latitude.is_some() != self.location.longitude.is_some() {
+            return invalid("location.latitude and location.longitude must be set together");
+        }
+        if self.location.latitude.map(|latitude| !(-90.0..=90.0).contains(&latitude)).unwrap_or(false) {
+            return invalid("location.latitude must be between -90 and 90");
+        }
+        if self.location.longitude.map(|longitude| !(-180.0..=180.0).contains(&longitude)).unwrap_or(false) {
+            return invalid("location.longitude must be between -180 and 180");
+        }
+        if let Some(Err(err)) = self.location.time_zone.as_ref().map(|time_zone| time_zone.parse::<Tz>()) {
+            return Err(ConfigError::Invalid(format!("location.time_zone is not a known time zone: {}", err)));
+        }
+        if self.timing.reconnect_backoff_min_ms == 0 {
+            return invalid("timing.reconnect_backoff_min_ms must not be 0");
+        }
+        if self.timing.reconnect_backoff_min_ms > self.timing.reconnect_backoff_max_ms {
+            return invalid("timing.reconnect_backoff_min_ms must not be larger than timing.reconnect_backoff_max_ms");
+        }
+        if self.timing.light_info_ttl_ms == Some(0) {
+            return invalid("timing.light_info_ttl_ms must not be 0, leave it unset to cache forever");
+        }
+        if self.timing.device_info_timeout_ms == 0 {
+            return invalid("timing.device_info_timeout_ms must not be 0");
+        }
+        if self.timing.command_timeout_ms == 0 {
+            return invalid("timing.command_timeout_ms must not be 0");
+        }
+        if self.timing.health_check_interval_ms == 0 {
+            return invalid("timing.health_check_interval_ms must not be 0");
+        }
+        if self.timing.shutdown_timeout_ms == 0 {
+            return invalid("timing.shutdown_timeout_ms must not be 0");
+        }
+
+        Ok(())
+    }
+}
+
+impl BluetoothConfig {
+    pub fn notify_characteristic_uuid(&self) -> Uuid {
+        // Checked when the config is loaded.
+        Uuid::parse_str(&self.notify_characteristic_uuid).unwrap()
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+}
+
+impl LocationConfig {
+    pub fn location(&self) -> Option<Location> {
+        Some(Location { latitude: self.latitude?, longitude: self.longitude? })
+    }
+
+    pub fn time_zone(&self) -> Option<Tz> {
+        // Checked when the config is loaded.
+        self.time_zone.as_ref().map(|time_zone| time_zone.parse().unwrap())
+    }
+}
+
+impl TimingConfig {
+    pub fn reconnect_backoff_min(&self) -> Duration {
+        Duration::from_millis(self.reconnect_backoff_min_ms)
+    }
+
+    pub fn reconnect_backoff_max(&self) -> Duration {
+        Duration::from_millis(self.reconnect_backoff_max_ms)
+    }
+
+    pub fn command_interval(&self) -> Duration {
+        Duration::from_millis(self.command_interval_ms)
+    }
+
+    pub fn light_info_ttl(&self) -> Option<Duration> {
+        self.light_info_ttl_ms.map(Duration::from_millis)
+    }
+
+    pub fn device_info_timeout(&self) -> Duration {
+        Duration::from_millis(self.device_info_timeout_ms)
+    }
+
+    pub fn command_retry_delay(&self) -> Duration {
+        Duration::from_millis(self.command_retry_delay_ms)
+    }
+
+    pub fn command_timeout(&self) -> Duration {
+        Duration::from_millis(self.command_timeout_ms)
+    }
+
+    pub fn health_check_interval(&self) -> Duration {
+        Duration::from_millis(self.health_check_interval_ms)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+}
+
+/// `HOME_LIGHT_*` variables that set a config value. Others, like `HOME_LIGHT_CONFIG`, are left
+/// out, so an unrelated variable doesn't keep the hub from starting.
+fn env_overrides() -> Env {
+    let known_keys = known_keys();
+    let env = Env::prefixed(ENV_PREFIX).split("__");
+    for (key, _) in env.iter() {
+        if key != CONFIG_PATH_ENV[ENV_PREFIX.len()..] && !known_keys.iter().any(|known_key| key == known_key.as_str()) {
+            eprintln!("Ignoring {}{}, it doesn't match a config value", ENV_PREFIX, key.as_str().replace('.', "__").to_uppercase());
+        }
+    }
+
+    env.filter(move |key| known_keys.iter().any(|known_key| key == known_key.as_str()))
+}
+
+/// Every config value as `section.field`.
+fn known_keys() -> Vec<String> {
+    let mut keys = Vec::new();
+    // Unset options are serialized as null, so they're listed too.
+    if let Ok(Value::Object(sections)) = json::to_value(Config::default()) {
+        for (section, fields) in sections.iter() {
+            if let Value::Object(fields) = fields {
+                keys.extend(fields.keys().map(|field| format!("{}.{}", section, field)));
+            }
+        }
+    }
+
+    keys
+}
+
+/// Collects every value given for `flag` in `args`, e.g. `--flag a --flag b`.
+fn flag_values(args: &[String], flag: &str) -> Result<Vec<String>, ConfigError> {
+    let mut values = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            let value = args.next().ok_or_else(|| ConfigError::Invalid(format!("{} requires a value", flag)))?;
+            values.push(value.clone());
+        }
+    }
+
+    Ok(values)
+}
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    /// The config file or an override couldn't be read or has the wrong shape.
+    Load(Box<rocket::figment::Error>),
+    /// The values were read but don't make sense together.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Load(error) => { write!(f, "Couldn't load config: {}", error) }
+            ConfigError::Invalid(message) => { write!(f, "Invalid config: {}", message) }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    /// Tests that change the environment, or load a config and so read it, hold this so they
+    /// don't see each other's variables.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// A test that failed while holding the lock doesn't fail the others.
+    fn lock_env() -> MutexGuard<'static, ()> {
+        ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| String::from(*arg)).collect()
+    }
+
+    fn invalid_message(config: &Config) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(message)) => { message }
+            other => { panic!("Expected the config to be invalid, got {:?}", other) }
+        }
+    }
+
+    #[test]
+    fn later_sources_take_precedence() {
+        let _env = lock_env();
+        let path = std::env::temp_dir().join(format!("home-light-hub-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "[server]\nport = 9000\n\n[timing]\ncommand_interval_ms = 250\nshutdown_timeout_ms = 1000\n").unwrap();
+        let path = path.to_str().unwrap();
+        std::env::set_var("HOME_LIGHT_TIMING__COMMAND_INTERVAL_MS", "300");
+        std::env::set_var("HOME_LIGHT_SERVER__PORT", "9100");
+
+        let from_env = Config::load(&args(&["--config", path]));
+        let from_args = Config::load(&args(&["--config", path, "--port", "9200"]));
+        std::env::remove_var("HOME_LIGHT_TIMING__COMMAND_INTERVAL_MS");
+        std::env::remove_var("HOME_LIGHT_SERVER__PORT");
+        let from_file = Config::load(&args(&["--config", path]));
+        std::fs::remove_file(path).unwrap();
+
+        let from_file = from_file.unwrap();
+        assert_eq!(from_file.server.port, 9000);
+        assert_eq!(from_file.timing.shutdown_timeout_ms, 1000);
+        // Left out of the file, so it's the default.
+        assert_eq!(from_file.timing.device_info_timeout_ms, TimingConfig::default().device_info_timeout_ms);
+
+        let from_env = from_env.unwrap();
+        assert_eq!(from_env.timing.command_interval_ms, 300);
+        assert_eq!(from_env.timing.shutdown_timeout_ms, 1000);
+        assert_eq!(from_env.server.port, 9100);
+        assert_eq!(from_args.unwrap().server.port, 9200);
+    }
+
+    #[test]
+    fn unrelated_environment_variables_are_ignored() {
+        let _env = lock_env();
+        std::env::set_var("HOME_LIGHT_UNRELATED", "1");
+        std::env::set_var("HOME_LIGHT_SERVER__NOT_A_SETTING", "1");
+        let config = Config::load(&args(&["--simulated-lights", "2"]));
+        std::env::remove_var("HOME_LIGHT_UNRELATED");
+        std::env::remove_var("HOME_LIGHT_SERVER__NOT_A_SETTING");
+
+        assert_eq!(config.unwrap().simulator.lights, Some(2));
+    }
+
+    #[test]
+    fn invalid_values_are_reported() {
+        assert!(Config::default().validate().is_ok());
+
+        let mut config = Config::default();
+        config.server.port = 0;
+        assert_eq!(invalid_message(&config), "server.port must not be 0");
+
+        let mut config = Config::default();
+        config.timing.reconnect_backoff_min_ms = 10_000;
+        assert_eq!(invalid_message(&config), "timing.reconnect_backoff_min_ms must not be larger than timing.reconnect_backoff_max_ms");
+
+        let mut config = Config::default();
+        config.bluetooth.notify_characteristic_uuid = String::from("not a uuid");
+        assert!(invalid_message(&config).starts_with("bluetooth.notify_characteristic_uuid"));
+
+        let mut config = Config::default();
+        config.location.latitude = Some(52.5);
+        assert_eq!(invalid_message(&config), "location.latitude and location.longitude must be set together");
+
+        let mut config = Config::default();
+        config.location.time_zone = Some(String::from("Mars/Base"));
+        assert!(invalid_message(&config).starts_with("location.time_zone"));
+    }
+
+    #[test]
+    fn invalid_flags_are_reported() {
+        let _env = lock_env();
+        assert!(matches!(Config::load(&args(&["--port"])), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::load(&args(&["--port", "http"])), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::load(&args(&["--config", "/nonexistent/home-light-hub.toml"])), Err(ConfigError::Invalid(_))));
+    }
+}
@@ -0,0 +1,187 @@
+use futures::{SinkExt, StreamExt};
+use rocket::serde::json;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::Shutdown;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::light::HSVColor;
+use crate::runner::{Light, LightEvent};
+use crate::websocket::Socket;
+
+/// What a client can ask for over a light's control channel. Color components are on the same
+/// scale as `HSVColor`, any left out keep their current value.
+#[derive(Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
+enum ControlMessage {
+    #[serde(rename = "set_color")]
+    Color { h: Option<f64>, s: Option<f64>, v: Option<f64> },
+    #[serde(rename = "set_brightness")]
+    Brightness { brightness: f64 },
+    #[serde(rename = "set_power")]
+    Power { on: bool },
+}
+
+/// Sent back when a message can't be handled, everything else sent back is a `LightEvent`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ControlReply {
+    Error { error: String },
+}
+
+/// Runs a control channel for `light` until the client goes away or the server shuts down.
+///
+/// Every change goes straight to the light's command queue, which replaces a color change still
+/// waiting to be written with the newer one. A dragged slider therefore sends at most one
+/// `SetLEDColor` per command interval and the light always ends up on the last value. Changes to
+/// the light are sent back as state events.
+pub(crate) async fn run(light: Light, mut socket: Socket, mut events: broadcast::Receiver<LightEvent>, mut shutdown: Shutdown) {
+    let address = light.address();
+    let mut color = light.cached_light_info()
+        .map(|(light_info, _)| light_info.color)
+        .unwrap_or(HSVColor { h: 0.0, s: 0.0, v: 1.0 });
+
+    loop {
+        let reply = select! {
+            message = socket.next() => {
+                match message {
+                    Some(Ok(Message::Text(text))) => { handle_message(&text, &light, &mut color) }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => { break }
+                    Some(Ok(_)) => { None }
+                }
+            }
+            event = events.recv() => {
+                match event {
+                    Ok(event) => { handle_event(&event, &address, &mut color) }
+                    Err(RecvError::Lagged(skipped)) => {
+                        eprintln!("Control channel fell behind, dropped {} events", skipped);
+                        None
+                    }
+                    Err(RecvError::Closed) => { break }
+                }
+            }
+            _ = &mut shutdown => { break }
+        };
+
+        if let Some(reply) = reply {
+            if socket.send(Message::Text(reply)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    let _ = socket.close(None).await;
+}
+
+/// Passes on changes to this channel's light. The color it's changed from is kept up to date, so
+/// changing only some components keeps what others set in the meantime.
+fn handle_event(event: &LightEvent, address: &str, color: &mut HSVColor) -> Option<String> {
+    if event.address() != address {
+        return None;
+    }
+    if let LightEvent::State { state, .. } = event {
+        *color = state.color.clone();
+    }
+
+    Some(to_json(event))
+}
+
+/// Applies a single message from the client, returning the reply if there is one.
+fn handle_message(text: &str, light: &Light, color: &mut HSVColor) -> Option<String> {
+    let message = match json::from_str::<ControlMessage>(text) {
+        Ok(message) => { message }
+        Err(error) => { return Some(to_json(&ControlReply::Error { error: format!("Invalid message: {}", error) })) }
+    };
+
+    // Outcomes aren't waited on, the state events tell the client where the light ended up.
+    let queued = match message {
+        ControlMessage::Color { h, s, v } => {
+            color.h = h.unwrap_or(color.h).clamp(0.0, 360.0);
+            color.s = s.unwrap_or(color.s).clamp(0.0, 1.0);
+            color.v = v.unwrap_or(color.v).clamp(0.0, 1.0);
+            light.set_color(color.clone())
+        }
+        ControlMessage::Brightness { brightness } => {
+            color.v = brightness.clamp(0.0, 1.0);
+            light.set_color(color.clone())
+        }
+        ControlMessage::Power { on } => { light.set_power(on) }
+    };
+
+    match queued {
+        Ok(_) => { None }
+        Err(reason) => { Some(to_json(&ControlReply::Error { error: reason.to_string() })) }
+    }
+}
+
+fn to_json<T: Serialize>(reply: &T) -> String {
+    // Replies only hold plain data.
+    json::to_string(reply).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::TimingConfig;
+    use crate::light::LightInfo;
+    use crate::runner::testing;
+
+    fn color(h: f64, s: f64, v: f64) -> HSVColor {
+        HSVColor { h, s, v }
+    }
+
+    async fn started_light() -> Light {
+        testing::started_light(0, &TimingConfig::default(), broadcast::channel(16).0).await
+    }
+
+    fn cached_color(light: &Light) -> [u8; 3] {
+        light.cached_light_info().unwrap().0.color.to_raw_data()
+    }
+
+    #[tokio::test]
+    async fn components_left_out_are_kept() {
+        let light = started_light().await;
+        let mut current = color(0.0, 0.0, 1.0);
+
+        assert_eq!(handle_message(r#"{"type": "set_color", "h": 240, "s": 1}"#, &light, &mut current), None);
+        assert_eq!(cached_color(&light), color(240.0, 1.0, 1.0).to_raw_data());
+        assert_eq!(handle_message(r#"{"type": "set_brightness", "brightness": 2.0}"#, &light, &mut current), None);
+        assert_eq!(cached_color(&light), color(240.0, 1.0, 1.0).to_raw_data());
+        assert_eq!(handle_message(r#"{"type": "set_brightness", "brightness": 0.5}"#, &light, &mut current), None);
+        assert_eq!(cached_color(&light), color(240.0, 1.0, 0.5).to_raw_data());
+
+        assert_eq!(handle_message(r#"{"type": "set_power", "on": false}"#, &light, &mut current), None);
+        assert!(!light.cached_light_info().unwrap().0.is_on);
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_answered_with_an_error() {
+        let light = started_light().await;
+        let mut current = color(0.0, 0.0, 1.0);
+
+        for text in ["not json", r#"{"type": "set_hue", "h": 10}"#, r#"{"type": "set_power", "on": true, "extra": 1}"#].iter() {
+            let reply = handle_message(text, &light, &mut current).unwrap();
+            assert!(reply.starts_with(r#"{"type":"error","error":"Invalid message"#), "{}", reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_from_elsewhere_are_kept_too() {
+        let light = started_light().await;
+        let mut current = color(0.0, 0.0, 1.0);
+        let state = |address: &str, color: HSVColor| LightEvent::State {
+            address: String::from(address),
+            state: LightInfo { name: String::from("Simulated Light 0"), is_on: true, color, animation: None, schedule: Vec::new() },
+        };
+
+        // Another light's change is neither passed on nor taken.
+        assert_eq!(handle_event(&state("simulated-1", color(120.0, 1.0, 1.0)), "simulated-0", &mut current), None);
+        assert_eq!(current.to_raw_data(), color(0.0, 0.0, 1.0).to_raw_data());
+
+        assert!(handle_event(&state("simulated-0", color(120.0, 1.0, 1.0)), "simulated-0", &mut current).is_some());
+        assert_eq!(handle_message(r#"{"type": "set_brightness", "brightness": 0.5}"#, &light, &mut current), None);
+        assert_eq!(cached_color(&light), color(120.0, 1.0, 0.5).to_raw_data());
+    }
+}
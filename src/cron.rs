@@ -0,0 +1,182 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+
+/// How far ahead to look for a matching minute, an expression that only matches on Feb 29th
+/// needs up to eight years.
+const SEARCH_DAYS: i64 = 8 * 366;
+
+/// A cron-style expression of five fields: minute, hour, day of month, month and day of week
+/// (0 or 7 is Sunday). Fields take `*`, numbers, ranges like `1-5`, steps like `*/15` and lists
+/// of those. Like cron, a minute matches if either day field does when both are restricted.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CronExpression {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ParseError(String);
+
+impl CronExpression {
+    /// The first matching minute after `after`, in `time_zone`. Minutes skipped by a clock change
+    /// don't match, minutes repeated by one match the first time only.
+    pub(crate) fn next_after<Tz: TimeZone>(&self, after: &DateTime<Tz>, time_zone: &Tz) -> Option<DateTime<Tz>> {
+        let local = after.naive_local();
+        let start = local.date().and_hms(local.hour(), local.minute(), 0) + Duration::minutes(1);
+        let mut date = start.date();
+        for _ in 0..SEARCH_DAYS {
+            if self.matches_date(date) {
+                for time in self.times(date) {
+                    if time < start {
+                        continue;
+                    }
+                    if let Some(next) = earliest(time_zone.from_local_datetime(&time)).filter(|next| next > after) {
+                        return Some(next);
+                    }
+                }
+            }
+            date = date.succ();
+        }
+
+        None
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if !has(self.months, date.month()) {
+            return false;
+        }
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => { day || weekday }
+            _ => { day && weekday }
+        }
+    }
+
+    fn times(&self, date: NaiveDate) -> impl Iterator<Item = NaiveDateTime> + '_ {
+        (0..24).filter(move |hour| has(self.hours, *hour))
+            .flat_map(move |hour| (0..60).filter(move |minute| has(self.minutes, *minute))
+                .map(move |minute| date.and_hms(hour, minute, 0)))
+    }
+}
+
+fn has(field: u64, value: u32) -> bool {
+    field & (1 << value) != 0
+}
+
+fn earliest<Tz: TimeZone>(result: LocalResult<DateTime<Tz>>) -> Option<DateTime<Tz>> {
+    match result {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => { Some(time) }
+        LocalResult::None => { None }
+    }
+}
+
+impl FromStr for CronExpression {
+    type Err = ParseError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(ParseError(format!("Expected 5 fields (minute hour day month weekday), got {}", fields.len())));
+        }
+
+        let mut weekdays = parse_field("weekday", fields[4], 0, 7)?;
+        // Sunday can be written as 7 too.
+        if has(weekdays, 7) {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(CronExpression {
+            minutes: parse_field("minute", fields[0], 0, 59)?,
+            hours: parse_field("hour", fields[1], 0, 23)?,
+            days: parse_field("day", fields[2], 1, 31)?,
+            months: parse_field("month", fields[3], 1, 12)?,
+            weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+}
+
+/// Parses one field into a bit set of the values it matches.
+fn parse_field(name: &str, field: &str, min: u32, max: u32) -> Result<u64, ParseError> {
+    let invalid = || ParseError(format!("Invalid {} field {:?}, values go from {} to {}", name, field, min, max));
+    let number = |value: &str| value.parse::<u32>().ok().filter(|value| (min..=max).contains(value)).ok_or_else(invalid);
+
+    let mut values = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => { (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or_else(invalid)?) }
+            None => { (item, 1) }
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => { (min, max) }
+            Some((first, last)) => { (number(first)?, number(last)?) }
+            // A single value with a step runs to the end, like `5/15`.
+            None if step > 1 => { (number(range)?, max) }
+            None => { (number(range)?, number(range)?) }
+        };
+        if first > last {
+            return Err(invalid());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            values |= 1 << value;
+        }
+    }
+
+    Ok(values)
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, Utc};
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        Utc.datetime_from_str(text, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn next(expression: &str, after: &str) -> DateTime<Utc> {
+        expression.parse::<CronExpression>().unwrap().next_after(&utc(after), &Utc).unwrap()
+    }
+
+    #[test]
+    fn next_matching_minute_is_found() {
+        assert_eq!(next("30 7 * * *", "2026-10-17 07:29:59"), utc("2026-10-17 07:30:00"));
+        assert_eq!(next("30 7 * * *", "2026-10-17 07:30:00"), utc("2026-10-18 07:30:00"));
+        assert_eq!(next("*/15 * * * *", "2026-10-17 23:50:10"), utc("2026-10-18 00:00:00"));
+        // Weekdays only, the 17th is a Saturday.
+        assert_eq!(next("0 6 * * 1-5", "2026-10-17 12:00:00"), utc("2026-10-19 06:00:00"));
+        assert_eq!(next("0 0 29 2 *", "2026-10-17 12:00:00"), utc("2028-02-29 00:00:00"));
+        // Either day field matches when both are given, the 18th is a Sunday.
+        assert_eq!(next("0 9 1 * 7", "2026-10-17 12:00:00"), utc("2026-10-18 09:00:00"));
+    }
+
+    #[test]
+    fn times_are_in_the_given_time_zone() {
+        let time_zone = FixedOffset::east(2 * 60 * 60);
+        let after = time_zone.ymd(2026, 10, 17).and_hms(8, 0, 0);
+        let expression: CronExpression = "30 7 * * *".parse().unwrap();
+        assert_eq!(expression.next_after(&after, &time_zone).unwrap(), time_zone.ymd(2026, 10, 18).and_hms(7, 30, 0));
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        for expression in ["* * * *", "60 * * * *", "* 5-1 * * *", "*/0 * * * *", "* * 0 * *", "mon * * * *"].iter() {
+            assert!(expression.parse::<CronExpression>().is_err(), "{:?}", expression);
+        }
+    }
+}
@@ -1,10 +1,14 @@
 
 use num::FromPrimitive;
+use std::error::Error;
+use std::fmt;
 
 use tokio::sync::mpsc::UnboundedSender;
 
 const DATA_BEGIN_BYTE: u8 = 0xFE;
 const DATA_END_BYTE: u8 = 0xFF;
+/// The length of a message's data is sent as a single byte.
+pub(crate) const MAX_MESSAGE_DATA_LENGTH: usize = u8::MAX as usize;
 
 #[derive(FromPrimitive, Clone, Copy, Debug)]
 pub(crate) enum HomeLightMessageType {
@@ -17,6 +21,45 @@ pub(crate) struct HomeLightMessage {
     pub data: Vec<u8>,
 }
 
+/// Something has more than the protocol has room for, e.g. data that doesn't fit in a frame.
+#[derive(Debug, PartialEq)]
+pub(crate) struct TooLong {
+    pub field: &'static str,
+    pub length: usize,
+    pub max: usize,
+}
+
+impl HomeLightMessage {
+    /// Frames the message the way the firmware sends it, data that doesn't fit in a frame is an
+    /// error. Cutting it off would leave a message that can't be parsed.
+    pub fn get_raw_data(&self) -> Result<Vec<u8>, TooLong> {
+        TooLong::check("message data", self.data.len(), MAX_MESSAGE_DATA_LENGTH)?;
+        let mut raw_data = vec![DATA_BEGIN_BYTE, self.message_type as u8, self.data.len() as u8];
+        raw_data.extend(&self.data);
+        raw_data.push(DATA_END_BYTE);
+
+        Ok(raw_data)
+    }
+}
+
+impl TooLong {
+    pub(crate) fn check(field: &'static str, length: usize, max: usize) -> Result<(), TooLong> {
+        if length > max {
+            return Err(TooLong { field, length, max });
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for TooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is {} long, only {} fit", self.field, self.length, self.max)
+    }
+}
+
+impl Error for TooLong {}
+
 pub(crate) struct HomeLightDecoder {
     is_in_readable_command: bool,
     current_message_type: Option<HomeLightMessageType>,
@@ -39,7 +82,7 @@ impl HomeLightDecoder {
 
     pub fn consume_data_packet(&mut self, data_packet: &[u8]) {
         for current_byte in data_packet {
-            if self.is_in_readable_command == false {
+            if !self.is_in_readable_command {
                 if *current_byte != DATA_BEGIN_BYTE {
                     continue;
                 } else {
@@ -113,3 +156,30 @@ impl HomeLightDecoder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn framed_messages_decode_back() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut decoder = HomeLightDecoder::new(tx);
+        let message = HomeLightMessage { message_type: HomeLightMessageType::DeviceInfo, data: vec![1, 2, 3] };
+        decoder.consume_data_packet(&message.get_raw_data().unwrap());
+
+        assert_eq!(rx.try_recv().unwrap().data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn data_too_long_for_a_frame_is_rejected() {
+        let message = HomeLightMessage { message_type: HomeLightMessageType::DeviceInfo, data: vec![7; MAX_MESSAGE_DATA_LENGTH] };
+        let raw_data = message.get_raw_data().unwrap();
+        assert_eq!(raw_data[2] as usize, MAX_MESSAGE_DATA_LENGTH);
+        assert_eq!(raw_data.last(), Some(&DATA_END_BYTE));
+
+        let message = HomeLightMessage { message_type: HomeLightMessageType::DeviceInfo, data: vec![7; MAX_MESSAGE_DATA_LENGTH + 1] };
+        assert_eq!(message.get_raw_data(), Err(TooLong { field: "message data", length: MAX_MESSAGE_DATA_LENGTH + 1, max: MAX_MESSAGE_DATA_LENGTH }));
+    }
+}
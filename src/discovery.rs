@@ -0,0 +1,79 @@
+use std::collections::HashSet;
+
+use btleplug::api::{BDAddr, Central, CentralEvent, Peripheral as _};
+use btleplug::platform::{Adapter, Peripheral};
+use futures::StreamExt;
+
+use crate::config::Config;
+use crate::runner;
+use crate::transport::{self, ble::BleTransport};
+
+/// Watches an adapter for lights for as long as the server runs, attaching each one to
+/// `peripheral_state` the first time it's seen.
+pub(crate) async fn run(adapter: Adapter, peripheral_state: runner::PeripheralState, config: Config) -> transport::Result<()> {
+    let mut seen = Seen { attached: HashSet::new(), rejected: HashSet::new() };
+    let mut events = adapter.events().await?;
+
+    println!("Starting scan...");
+    adapter.start_scan().await?;
+
+    // Peripherals the adapter already knows about don't necessarily show up as discovered again.
+    let known_peripherals = adapter.peripherals().await?;
+    for peripheral in known_peripherals {
+        attach_if_matching(peripheral, &mut seen, &peripheral_state, &config).await;
+    }
+
+    while let Some(event) = events.next().await {
+        match event {
+            CentralEvent::DeviceDiscovered(address) | CentralEvent::DeviceUpdated(address) => {
+                if seen.attached.contains(&address) || seen.rejected.contains(&address) {
+                    continue;
+                }
+                // btleplug's errors can't be held across an await, so convert before matching.
+                let peripheral = adapter.peripheral(address).await.map_err(transport::Error::from);
+                match peripheral {
+                    Ok(peripheral) => { attach_if_matching(peripheral, &mut seen, &peripheral_state, &config).await }
+                    Err(err) => { eprintln!("Error looking up discovered peripheral {}: {}", address, err) }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    eprintln!("--- !!Discovery Event Stream Closed!! ---");
+
+    Ok(())
+}
+
+/// Peripherals that don't need to be looked at again. Lights keep their name, so one whose name
+/// didn't match won't match later either.
+struct Seen {
+    attached: HashSet<BDAddr>,
+    rejected: HashSet<BDAddr>,
+}
+
+async fn attach_if_matching(peripheral: Peripheral, seen: &mut Seen, peripheral_state: &runner::PeripheralState, config: &Config) {
+    let address = peripheral.address();
+    // The name usually arrives in a later update, so a peripheral without one is looked at again.
+    let local_name = match peripheral.properties().await {
+        Ok(Some(properties)) => { properties.local_name }
+        _ => { None }
+    };
+    let local_name = match local_name {
+        Some(local_name) => { local_name }
+        None => { return }
+    };
+
+    if !config.bluetooth.name_filters.iter().any(|filter| local_name.contains(filter.as_str())) {
+        seen.rejected.insert(address);
+        return;
+    }
+    if !seen.attached.insert(address) {
+        return;
+    }
+
+    println!("Found: {:?} ({})", &local_name, address);
+    // The light is supervised from here on, it's reconnected whenever it drops.
+    let transport = BleTransport::new(peripheral, &config.bluetooth);
+    peripheral_state.add_light(runner::start(transport, &config.timing, peripheral_state.events()));
+}
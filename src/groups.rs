@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+
+use crate::runner::{Light, PeripheralState};
+use crate::store::{Collection, Named};
+
+/// A named set of lights that are changed together, e.g. a room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Group {
+    pub name: String,
+    /// Addresses of the members.
+    pub lights: Vec<String>,
+}
+
+/// Groups by name, saved to disk on every change.
+pub(crate) type GroupStore = Collection<Group>;
+
+/// The state of a group as a whole, from what its members last reported.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct GroupState {
+    /// Whether any member is on.
+    pub is_on: bool,
+    /// Average brightness of the members that are on, `None` if none are.
+    pub brightness: Option<f64>,
+    /// How many members have reported their state, the others aren't counted above.
+    pub reported: usize,
+}
+
+impl Named for Group {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Group {
+    /// The members that are known to the hub, members that haven't been found (yet) are left out.
+    pub(crate) fn members(&self, state: &PeripheralState) -> Vec<Light> {
+        self.lights.iter().filter_map(|address| state.find(address)).collect()
+    }
+}
+
+/// Sums up the cached state of `lights`, without asking any of them.
+pub(crate) fn aggregate_state(lights: &[Light]) -> GroupState {
+    let light_infos: Vec<_> = lights.iter().filter_map(|light| light.cached_light_info()).collect();
+    let brightnesses: Vec<f64> = light_infos.iter()
+        .filter(|(light_info, _)| light_info.is_on)
+        .map(|(light_info, _)| light_info.color.v)
+        .collect();
+    let brightness = if brightnesses.is_empty() {
+        None
+    } else {
+        Some(brightnesses.iter().sum::<f64>() / brightnesses.len() as f64)
+    };
+
+    GroupState { is_on: !brightnesses.is_empty(), brightness, reported: light_infos.len() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    use crate::config::TimingConfig;
+    use crate::light::HSVColor;
+    use crate::runner::testing;
+
+    async fn started_light(index: usize) -> Light {
+        testing::started_light(index, &TimingConfig::default(), broadcast::channel(16).0).await
+    }
+
+    #[tokio::test]
+    async fn group_state_sums_up_the_members_that_are_on() {
+        let lights = vec![started_light(0).await, started_light(1).await, started_light(2).await];
+        lights[0].set_color(HSVColor { h: 0.0, s: 1.0, v: 0.2 }).unwrap();
+        lights[1].set_color(HSVColor { h: 0.0, s: 1.0, v: 0.6 }).unwrap();
+        lights[2].set_power(false).unwrap();
+
+        let state = aggregate_state(&lights);
+        assert!(state.is_on);
+        assert!((state.brightness.unwrap() - 0.4).abs() < 1e-9);
+        assert_eq!(state.reported, 3);
+
+        lights[0].set_power(false).unwrap();
+        lights[1].set_power(false).unwrap();
+        assert_eq!(aggregate_state(&lights), GroupState { is_on: false, brightness: None, reported: 3 });
+    }
+}
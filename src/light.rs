@@ -1,6 +1,22 @@
-
 use num::FromPrimitive;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::fmt;
+use std::string::FromUtf8Error;
+
+use crate::decoder::{TooLong, MAX_MESSAGE_DATA_LENGTH};
+
+/// The light reports its animation and schedule in its DeviceInfo message, which has to fit in a
+/// single frame. Animations and schedules get a fixed share of it, the name gets what's left.
+pub(crate) const MAX_KEYFRAMES: usize = 16;
+pub(crate) const MAX_SCHEDULE_ENTRIES: usize = 16;
+/// Longest name in bytes, the space a DeviceInfo message has left with the longest animation and
+/// schedule: the name's terminator, power and color state, the animation's loop mode and
+/// keyframe count, and the schedule count.
+pub(crate) const MAX_NAME_LENGTH: usize = MAX_MESSAGE_DATA_LENGTH
+    - (3 + 2 + MAX_KEYFRAMES * KEYFRAME_LENGTH + 1 + MAX_SCHEDULE_ENTRIES * SCHEDULE_ENTRY_LENGTH);
+const KEYFRAME_LENGTH: usize = 5;
+const SCHEDULE_ENTRY_LENGTH: usize = 8;
 
 #[derive(FromPrimitive, Clone, Copy)]
 enum ColorState {
@@ -8,53 +24,384 @@ enum ColorState {
     Animating = 0x01,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HSVColor {
     pub h: f64,
     pub s: f64,
     pub v: f64,
 }
 
-#[derive(Debug, Clone)]
+impl HSVColor {
+    pub fn from_raw_data(data: [u8; 3]) -> Self {
+        HSVColor {
+            h: (f64::from(data[0]) / 255.0) * 360.0,
+            s: f64::from(data[1]) / 255.0,
+            v: f64::from(data[2]) / 255.0,
+        }
+    }
+
+    pub fn to_raw_data(&self) -> [u8; 3] {
+        [
+            (self.h.clamp(0.0, 360.0) / 360.0 * 255.0).round() as u8, // H
+            (self.s.clamp(0.0, 1.0) * 255.0).round() as u8,           // S
+            (self.v.clamp(0.0, 1.0) * 255.0).round() as u8            // V
+        ]
+    }
+
+    /// This color with the components that are given replaced.
+    pub fn with_components(&self, h: Option<f64>, s: Option<f64>, v: Option<f64>) -> Self {
+        HSVColor { h: h.unwrap_or(self.h), s: s.unwrap_or(self.s), v: v.unwrap_or(self.v) }
+    }
+}
+
+/// What the light does once it reaches the last keyframe of an animation.
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LoopMode {
+    /// Stop on the last keyframe.
+    Once = 0x00,
+    /// Jump back to the first keyframe and start over.
+    Repeat = 0x01,
+    /// Play the keyframes backwards, then forwards again.
+    PingPong = 0x02,
+}
+
+/// A single step of an animation, the light fades to `color` over `duration_ms`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Keyframe {
+    pub color: HSVColor,
+    pub duration_ms: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Animation {
+    pub loop_mode: LoopMode,
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl Animation {
+    /// Encodes the animation as `[loop mode, keyframe count, (h, s, v, duration hi, duration lo)...]`.
+    /// More than `MAX_KEYFRAMES` keyframes is an error.
+    pub fn to_raw_data(&self) -> Result<Vec<u8>, TooLong> {
+        TooLong::check("keyframes", self.keyframes.len(), MAX_KEYFRAMES)?;
+        let mut data = vec![self.loop_mode as u8, self.keyframes.len() as u8];
+        for keyframe in self.keyframes.iter() {
+            data.extend(keyframe.color.to_raw_data());
+            data.extend(keyframe.duration_ms.to_be_bytes());
+        }
+
+        Ok(data)
+    }
+}
+
+/// An entry in the light's on-device schedule storage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ScheduleEntry {
+    /// Storage slot on the device, writing to an occupied slot replaces its entry.
+    pub slot: u8,
+    /// Bitmask of the days this entry fires on, bit 0 is Sunday.
+    pub days: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub is_on: bool,
+    pub color: HSVColor,
+}
+
+impl ScheduleEntry {
+    /// Encodes the entry as `[slot, days, hour, minute, is on, h, s, v]`.
+    pub fn to_raw_data(&self) -> Vec<u8> {
+        let mut data = vec![self.slot, self.days, self.hour, self.minute, self.is_on as u8];
+        data.extend(self.color.to_raw_data());
+
+        data
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub(crate) struct LightInfo {
     pub name: String,
     pub is_on: bool,
+    /// The solid color of the light, or the first keyframe if it is animating.
     pub color: HSVColor,
+    pub animation: Option<Animation>,
+    pub schedule: Vec<ScheduleEntry>,
 }
 
 impl LightInfo {
-    pub fn from_raw_data(data: &[u8]) -> Result<Self, Box<dyn Error>> {
-        let mut info = LightInfo {
-            name: String::from(""),
-            is_on: false,
-            color: HSVColor { h: 0.0, s: 0.0, v: 0.0 }
+    /// Parses a DeviceInfo payload laid out as:
+    ///
+    /// `[name..., 0x00, is on, color state, (h, s, v) | animation, schedule count, schedule entries...]`
+    ///
+    /// Older firmware stops after the color, so a missing schedule section is treated as empty.
+    pub fn from_raw_data(data: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = RawDataReader::new(data);
+
+        let name_length = data.iter().position(|&byte| byte == 0).ok_or(ParseError::Truncated("name"))?;
+        let name = String::from_utf8(reader.next_slice(name_length, "name")?.to_vec())?;
+        reader.next_byte("name")?;
+
+        let is_on = reader.next_byte("power state")? != 0;
+
+        let color_state = reader.next_byte("color state")?;
+        let (color, animation) = match ColorState::from_u8(color_state) {
+            Some(ColorState::Solid) => {
+                (HSVColor::from_raw_data(reader.next_array("color")?), None)
+            }
+            Some(ColorState::Animating) => {
+                let animation = Animation::read(&mut reader)?;
+                let color = animation.keyframes.first()
+                    .map(|keyframe| keyframe.color.clone())
+                    .unwrap_or(HSVColor { h: 0.0, s: 0.0, v: 0.0 });
+                (color, Some(animation))
+            }
+            None => { return Err(ParseError::UnknownColorState(color_state)) }
         };
 
-        info.name = String::from_utf8(data.iter().map(|byte| *byte).take_while(|&byte| byte != 0).collect())?;
+        let mut schedule = Vec::new();
+        if !reader.is_empty() {
+            let entry_count = reader.next_byte("schedule count")?;
+            for _ in 0..entry_count {
+                schedule.push(ScheduleEntry::read(&mut reader)?);
+            }
+        }
+
+        Ok(LightInfo { name, is_on, color, animation, schedule })
+    }
+
+    /// Encodes the info the same way the firmware does in its DeviceInfo message. Info past the
+    /// limits above is an error, it wouldn't fit in a frame.
+    pub fn to_raw_data(&self) -> Result<Vec<u8>, TooLong> {
+        TooLong::check("name", self.name.len(), MAX_NAME_LENGTH)?;
+        TooLong::check("schedule", self.schedule.len(), MAX_SCHEDULE_ENTRIES)?;
+        let mut data = self.name.as_bytes().to_vec();
+        data.push(0);
+        data.push(self.is_on as u8);
+
+        match &self.animation {
+            None => {
+                data.push(ColorState::Solid as u8);
+                data.extend(self.color.to_raw_data());
+            }
+            Some(animation) => {
+                data.push(ColorState::Animating as u8);
+                data.extend(animation.to_raw_data()?);
+            }
+        }
+
+        data.push(self.schedule.len() as u8);
+        for entry in self.schedule.iter() {
+            data.extend(entry.to_raw_data());
+        }
+
+        Ok(data)
+    }
+}
+
+impl Animation {
+    pub fn from_raw_data(data: &[u8]) -> Result<Self, ParseError> {
+        Self::read(&mut RawDataReader::new(data))
+    }
+
+    fn read(reader: &mut RawDataReader) -> Result<Self, ParseError> {
+        let raw_loop_mode = reader.next_byte("animation loop mode")?;
+        let loop_mode = LoopMode::from_u8(raw_loop_mode).ok_or(ParseError::UnknownLoopMode(raw_loop_mode))?;
 
-        let mut remaining_data = data.iter().skip(info.name.len() + 1);
-        
-        if let Some(is_on) = remaining_data.nth(0) {
-            info.is_on = *is_on != 0;
+        let keyframe_count = reader.next_byte("animation keyframe count")?;
+        let mut keyframes = Vec::with_capacity(keyframe_count as usize);
+        for _ in 0..keyframe_count {
+            let color = HSVColor::from_raw_data(reader.next_array("keyframe color")?);
+            let duration_ms = u16::from_be_bytes(reader.next_array("keyframe duration")?);
+            keyframes.push(Keyframe { color, duration_ms });
         }
 
-        if let Some(color_state) = remaining_data.nth(0) {
-            match ColorState::from_u8(*color_state) {
-                Some(ColorState::Solid) => {
-                    if let (Some(first), Some(second), Some(third)) = (remaining_data.nth(0), remaining_data.nth(0), remaining_data.nth(0)) {
-                        let h = (f64::from(*first) / 255.0) * 360.0;
-                        let s = f64::from(*second) / 255.0;
-                        let v = f64::from(*third) / 255.0;
+        Ok(Animation { loop_mode, keyframes })
+    }
+}
+
+impl ScheduleEntry {
+    pub fn from_raw_data(data: &[u8]) -> Result<Self, ParseError> {
+        Self::read(&mut RawDataReader::new(data))
+    }
+
+    fn read(reader: &mut RawDataReader) -> Result<Self, ParseError> {
+        let [slot, days, hour, minute, is_on] = reader.next_array("schedule entry")?;
+        let color = HSVColor::from_raw_data(reader.next_array("schedule entry color")?);
+
+        Ok(ScheduleEntry { slot, days, hour, minute, is_on: is_on != 0, color })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ParseError {
+    /// The data ended while reading the named field.
+    Truncated(&'static str),
+    InvalidName(FromUtf8Error),
+    UnknownColorState(u8),
+    UnknownLoopMode(u8),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Truncated(field) => { write!(f, "Data ended while reading {}", field) }
+            ParseError::InvalidName(error) => { write!(f, "Invalid name: {}", error) }
+            ParseError::UnknownColorState(value) => { write!(f, "Unknown color state: {:#04x}", value) }
+            ParseError::UnknownLoopMode(value) => { write!(f, "Unknown loop mode: {:#04x}", value) }
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<FromUtf8Error> for ParseError {
+    fn from(error: FromUtf8Error) -> Self {
+        ParseError::InvalidName(error)
+    }
+}
+
+struct RawDataReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> RawDataReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        RawDataReader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn next_slice(&mut self, length: usize, field: &'static str) -> Result<&'a [u8], ParseError> {
+        if self.data.len() < length {
+            return Err(ParseError::Truncated(field));
+        }
+        let (slice, rest) = self.data.split_at(length);
+        self.data = rest;
+
+        Ok(slice)
+    }
+
+    fn next_array<const N: usize>(&mut self, field: &'static str) -> Result<[u8; N], ParseError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.next_slice(N, field)?);
+
+        Ok(array)
+    }
+
+    fn next_byte(&mut self, field: &'static str) -> Result<u8, ParseError> {
+        let [byte] = self.next_array(field)?;
+
+        Ok(byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_info(tail: &[u8]) -> Vec<u8> {
+        let mut data = b"Desk\0".to_vec();
+        data.extend(tail);
+        data
+    }
+
+    #[test]
+    fn parses_solid_color_without_schedule() {
+        let info = LightInfo::from_raw_data(&device_info(&[0x01, 0x00, 0, 255, 255])).unwrap();
+        assert_eq!(info.name, "Desk");
+        assert!(info.is_on);
+        assert_eq!(info.color.to_raw_data(), [0, 255, 255]);
+        assert!(info.animation.is_none());
+        assert!(info.schedule.is_empty());
+    }
+
+    #[test]
+    fn parses_animation_and_schedule() {
+        let info = LightInfo::from_raw_data(&device_info(&[
+            0x01, 0x01,
+            0x01, 2, 85, 255, 255, 0x03, 0xE8, 170, 255, 128, 0x00, 0x64,
+            1, 2, 0b0011_1110, 6, 45, 1, 0, 0, 255,
+        ])).unwrap();
 
-                        info.color = HSVColor { h, s, v };
-                    }
-                }
-                _ => {}
+        let animation = info.animation.unwrap();
+        assert_eq!(animation.loop_mode, LoopMode::Repeat);
+        assert_eq!(animation.keyframes.len(), 2);
+        assert_eq!(animation.keyframes[0].duration_ms, 1_000);
+        assert_eq!(animation.keyframes[1].duration_ms, 100);
+        assert_eq!(info.color.to_raw_data(), [85, 255, 255]);
+
+        assert_eq!(info.schedule.len(), 1);
+        let entry = &info.schedule[0];
+        assert_eq!((entry.slot, entry.days, entry.hour, entry.minute, entry.is_on), (2, 0b0011_1110, 6, 45, true));
+        assert_eq!(entry.color.to_raw_data(), [0, 0, 255]);
+    }
+
+    #[test]
+    fn encoded_animation_parses_back() {
+        let animation = Animation {
+            loop_mode: LoopMode::PingPong,
+            keyframes: vec![Keyframe { color: HSVColor { h: 0.0, s: 1.0, v: 1.0 }, duration_ms: 500 }],
+        };
+        let mut data = device_info(&[0x00, 0x01]);
+        data.extend(animation.to_raw_data().unwrap());
+        data.push(0);
+
+        let parsed = LightInfo::from_raw_data(&data).unwrap().animation.unwrap();
+        assert_eq!(parsed.loop_mode, LoopMode::PingPong);
+        assert_eq!(parsed.keyframes[0].duration_ms, 500);
+    }
+
+    #[test]
+    fn device_info_at_the_limits_fits_in_a_frame() {
+        let color = HSVColor { h: 0.0, s: 1.0, v: 1.0 };
+        let entry = ScheduleEntry { slot: 0, days: 0x7F, hour: 7, minute: 0, is_on: true, color: color.clone() };
+        let mut info = LightInfo {
+            name: "n".repeat(MAX_NAME_LENGTH),
+            is_on: true,
+            color: color.clone(),
+            animation: Some(Animation { loop_mode: LoopMode::Once, keyframes: vec![Keyframe { color, duration_ms: 10 }; MAX_KEYFRAMES] }),
+            schedule: vec![entry.clone(); MAX_SCHEDULE_ENTRIES],
+        };
+        let data = info.to_raw_data().unwrap();
+        assert_eq!(data.len(), MAX_MESSAGE_DATA_LENGTH);
+        let parsed = LightInfo::from_raw_data(&data).unwrap();
+        assert_eq!(parsed.name, info.name);
+        assert_eq!(parsed.animation.unwrap().keyframes.len(), MAX_KEYFRAMES);
+        assert_eq!(parsed.schedule.len(), MAX_SCHEDULE_ENTRIES);
+
+        // One more of anything doesn't fit.
+        info.schedule.push(entry);
+        assert_eq!(info.to_raw_data().unwrap_err().field, "schedule");
+        info.schedule.pop();
+        info.name.push('n');
+        assert_eq!(info.to_raw_data().unwrap_err().field, "name");
+        info.name.pop();
+        let keyframe = info.animation.as_ref().unwrap().keyframes[0].clone();
+        info.animation.as_mut().unwrap().keyframes.push(keyframe);
+        assert_eq!(info.to_raw_data().unwrap_err().field, "keyframes");
+    }
+
+    #[test]
+    fn reports_truncated_data() {
+        let truncated = [
+            (b"Desk".to_vec(), "name"),
+            (device_info(&[]), "power state"),
+            (device_info(&[0x01, 0x00, 0, 255]), "color"),
+            (device_info(&[0x01, 0x01, 0x00, 1, 0, 0, 0, 0x01]), "keyframe duration"),
+            (device_info(&[0x01, 0x00, 0, 0, 0, 1, 0, 0, 0]), "schedule entry"),
+        ];
+        for (data, field) in truncated.iter() {
+            match LightInfo::from_raw_data(data) {
+                Err(ParseError::Truncated(truncated_field)) => { assert_eq!(truncated_field, *field) }
+                other => { panic!("Expected truncation in {}, got {:?}", field, other) }
             }
         }
-        
-        // Ignore the rest of the data, it contains animation/schedule info which we don't support
+    }
 
-        Ok(info)
+    #[test]
+    fn reports_unknown_states() {
+        assert!(matches!(LightInfo::from_raw_data(&device_info(&[0x01, 0x07])), Err(ParseError::UnknownColorState(0x07))));
+        assert!(matches!(LightInfo::from_raw_data(&device_info(&[0x01, 0x01, 0x09, 0])), Err(ParseError::UnknownLoopMode(0x09))));
     }
 }
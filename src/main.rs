@@ -4,25 +4,38 @@
 #[macro_use] extern crate num_derive;
 #[macro_use] extern crate rocket;
 
+mod api;
+mod config;
+mod control;
+mod cron;
 mod decoder;
+mod discovery;
+mod groups;
 mod light;
 mod runner;
+mod scenes;
+mod schedules;
 mod peripheral;
+mod queue;
+mod simulator;
+mod solar;
+mod store;
+mod transition;
+mod transport;
+mod websocket;
 
 //use rocket::config::{Config, Environment};
 
-use btleplug::api::{bleuuid::uuid_from_u16, Central, Manager as _, Peripheral};
+use btleplug::api::Manager as _;
 use btleplug::platform::Manager;
 use std::error::Error;
-use std::time::Duration;
-use tokio::time;
-use uuid::Uuid;
 
-/// Only devices whose name contains this string will be tried.
-const PERIPHERAL_NAME_MATCH_FILTER_1: &str = "TEST_DEVICE";
-const PERIPHERAL_NAME_MATCH_FILTER_2: &str = "Bluno";
-/// UUID of the characteristic for which we should subscribe to notifications.
-const NOTIFY_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xDFB1);
+use config::Config;
+use groups::GroupStore;
+use scenes::SceneStore;
+use schedules::{Clock, Hub, Scheduler};
+use simulator::{SimulatedLight, SimulatedTransport};
+use transport::serial::SerialTransport;
 
 #[tokio::main]
 async fn main() {
@@ -33,68 +46,101 @@ async fn main() {
 
 async fn start() -> Result<(), Box<dyn Error>> {
     //pretty_env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = Config::load(&args)?;
+
+    // Lights on other transports are mostly used for development, only scan for real ones when
+    // none were asked for.
+    let peripheral_state = runner::PeripheralState::new();
+    if let Some(count) = config.simulator.lights {
+        start_simulated_lights(count, &peripheral_state, &config);
+    }
+    if !config.serial.ports.is_empty() {
+        start_serial_lights(&peripheral_state, &config);
+    }
+
+    if config.simulator.lights.is_none() && config.serial.ports.is_empty() {
+        start_bluetooth_discovery(&peripheral_state, &config).await?;
+    }
+
+    let scenes = SceneStore::load(&config.storage.scenes_path)?;
+    let groups = GroupStore::load(&config.storage.groups_path)?;
+    let clock = Clock { time_zone: config.location.time_zone(), location: config.location.location() };
+    let scheduler = Scheduler::load(&config.storage.schedules_path, clock)?;
+    let scheduler_task = scheduler.start(Hub { state: peripheral_state.clone(), scenes: scenes.clone(), groups: groups.clone() });
+
+    println!("Launching Rocket!");
+
+    let figment = rocket::Config::figment()
+        .merge(("port", config.server.port));
+    // The plain-text routes at the root are kept for existing Homebridge setups, new clients
+    // should use the JSON API under /api/v1.
+    // Rocket stops on SIGINT and SIGTERM, the lights are disconnected once it has.
+    let launched = rocket::custom(figment)
+        .manage(peripheral_state.clone())
+        .manage(scenes)
+        .manage(groups)
+        .manage(scheduler)
+        .mount("/", routes![
+            runner::light_state,
+            runner::get_power_state,
+            runner::set_power_state,
+            runner::get_brightness,
+            runner::set_brightness,
+            runner::get_hue,
+            runner::set_hue,
+            runner::get_saturation,
+            runner::set_saturation
+        ])
+        .mount("/api/v1", api::routes())
+        .register("/api/v1", api::catchers())
+        .launch().await;
+
+    // Nothing should be scheduled while the lights are disconnected.
+    scheduler_task.abort();
+    println!("Disconnecting lights...");
+    peripheral_state.shutdown(config.timing.shutdown_timeout()).await;
+    launched?;
+
+    Ok(())
+}
+
+fn start_simulated_lights(count: usize, peripheral_state: &runner::PeripheralState, config: &Config) {
+    for index in 0..count {
+        let name = format!("Simulated Light {}", index);
+        println!("Starting: {:?}", &name);
+        let transport = SimulatedTransport::new(&format!("simulated-{}", index), SimulatedLight::new(&name));
+        peripheral_state.add_light(runner::start(transport, &config.timing, peripheral_state.events()));
+    }
+}
+
+fn start_serial_lights(peripheral_state: &runner::PeripheralState, config: &Config) {
+    for path in config.serial.ports.iter() {
+        println!("Opening serial port: {:?}", path);
+        let transport = SerialTransport::new(path, config.serial.baud_rate);
+        peripheral_state.add_light(runner::start(transport, &config.timing, peripheral_state.events()));
+    }
+}
+
+/// Starts discovering lights on every Bluetooth adapter, they're added to `peripheral_state` in
+/// the background as they show up.
+async fn start_bluetooth_discovery(peripheral_state: &runner::PeripheralState, config: &Config) -> Result<(), Box<dyn Error>> {
     let manager = Manager::new().await?;
     let adapter_list = manager.adapters().await?;
     if adapter_list.is_empty() {
         eprintln!("No Bluetooth adapters found");
     }
 
-    for adapter in adapter_list.iter() {
+    for adapter in adapter_list.into_iter() {
         println!("Adapter: {:?}", adapter);
-        println!("Starting scan...");
-        adapter
-            .start_scan()
-            .await
-            .expect("Can't scan BLE adapter for connected devices...");
-        time::sleep(Duration::from_secs(2)).await;
-        adapter.stop_scan().await.expect("Can't stop scan...");
-        let peripherals = adapter.peripherals().await?;
-
-        let mut run_states: [Option<_>; 2] = [None, None];
-        //let mut run_states = Vec::new();
-
-        for peripheral in peripherals.into_iter() {
-            let properties = peripheral.properties().await.unwrap();
-            let local_name = properties
-                .unwrap()
-                .local_name
-                .unwrap_or(String::from("(peripheral name unknown)"));
-
-            println!("Addr: {}", peripheral.address().to_string());
-            if local_name.contains(PERIPHERAL_NAME_MATCH_FILTER_1) {
-                println!("Found: {:?}", &local_name);
-                run_states[0] = Some(runner::start(&peripheral).await.unwrap());
+        let peripheral_state = peripheral_state.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(err) = discovery::run(adapter, peripheral_state, config).await {
+                eprintln!("Error discovering peripherals: {}", err);
             }
-//            if local_name.contains(PERIPHERAL_NAME_MATCH_FILTER_2) {
-//                println!("Found: {:?}", &local_name);
-//                run_states[1] = Some(runner::start(&peripheral).await.unwrap());
-//            }
-        }
-
-        println!("Finished checking peripherals");
-
-        let peripheral_state = runner::PeripheralState::new(run_states.iter_mut().filter_map(|x| x.take()).collect());
-
-        println!("Launching Rocket!");
-
-        let figment = rocket::Config::figment()
-            .merge(("port", 8000));
-        rocket::custom(figment)
-            .manage(peripheral_state)
-            .mount("/", routes![
-                runner::light_state,
-                runner::get_power_state,
-                runner::set_power_state,
-                runner::get_brightness,
-                runner::set_brightness,
-                runner::get_hue,
-                runner::set_hue,
-                runner::get_saturation,
-                runner::set_saturation
-            ])
-            .launch().await.unwrap();
+        });
     }
 
     Ok(())
 }
-
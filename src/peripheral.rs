@@ -1,55 +1,97 @@
 use futures::StreamExt;
-use std::sync::atomic::Ordering;
-use btleplug::api::{Characteristic, CharPropFlags, Peripheral as _, WriteType};
-use btleplug::platform::Peripheral;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::select;
 use tokio::task::JoinHandle;
-use tokio::sync::mpsc;
-use tokio::time::{timeout, sleep, Duration};
+use tokio::sync::{mpsc, watch, Mutex as AsyncMutex, Notify};
+use tokio::time::{interval, timeout, sleep, Duration, Instant};
 
-use crate::light::HSVColor;
-use crate::NOTIFY_CHARACTERISTIC_UUID;
+use crate::light::{Animation, HSVColor, ScheduleEntry, MAX_KEYFRAMES, MAX_NAME_LENGTH};
+use crate::config::TimingConfig;
 use crate::decoder;
-use crate::runner;
+use crate::queue::{self, CommandOutcome, CommandQueue, CommandReceiver, DropReason};
+use crate::transport::{self, Transport};
 
 const COMMAND_START_BYTE: u8 = 0xFE;
 const COMMAND_END_BYTE: u8 = 0xFF;
+/// The length of a command's data is sent as a single byte.
+pub(crate) const MAX_COMMAND_DATA_LENGTH: usize = u8::MAX as usize;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub(crate) enum Command {
+    SetName(String),
+    Echo(Vec<u8>),
     SetLEDColor(HSVColor),
     SetBrightness(f64),
 
     GetDeviceInfo,
+
+    SetAnimation(Animation),
+
+    GetColorInfo,
+
+    SetSchedule(ScheduleEntry),
+
+    /// Clears a single schedule slot, or every slot when `None`.
+    ClearSchedule(Option<u8>),
 }
 
 impl Command {
     fn get_command_code(&self) -> u8 {
         match self {
+            Command::SetName(_) => { 0x00 }
+            Command::Echo(_) => { 0x01 }
             Command::SetLEDColor(_) => { 0x02 }
             Command::SetBrightness(_) => { 0x03 }
             Command::GetDeviceInfo => { 0x04 }
+            Command::SetAnimation(_) => { 0x05 }
+            Command::GetColorInfo => { 0x06 }
+            Command::SetSchedule(_) => { 0x07 }
+            Command::ClearSchedule(_) => { 0x08 }
         }
     }
 
     fn get_command_data(&self) -> Vec<u8> {
         match self {
+            Command::SetName(name) => {
+                // The light reports its name back with the rest of its state, which has to fit in
+                // a single frame. Make sure we don't split a multi-byte character when truncating.
+                let mut end = name.len().min(MAX_NAME_LENGTH);
+                while !name.is_char_boundary(end) {
+                    end -= 1;
+                }
+                name.as_bytes()[..end].to_vec()
+            }
+            Command::Echo(data) => {
+                data.iter().copied().take(MAX_COMMAND_DATA_LENGTH).collect()
+            }
             Command::SetLEDColor(color) => {
-                vec![
-                    (color.h / 360.0 * 255.0).round() as u8, // H
-                    (color.s * 255.0).round() as u8,         // S
-                    (color.v * 255.0).round() as u8          // V
-                ]
+                color.to_raw_data().to_vec()
             }
             Command::SetBrightness(brightness) => {
                 vec![(brightness.clamp(0.0, 1.0) * 255.0).round() as u8]
             }
-            Command::GetDeviceInfo => { 
-                vec![] 
+            Command::GetDeviceInfo => {
+                vec![]
+            }
+            Command::SetAnimation(animation) => {
+                // Like names, animations are cut to what the light can report back.
+                let keyframes = animation.keyframes[..animation.keyframes.len().min(MAX_KEYFRAMES)].to_vec();
+                Animation { keyframes, ..animation.clone() }.to_raw_data().unwrap()
+            }
+            Command::GetColorInfo => {
+                vec![]
+            }
+            Command::SetSchedule(entry) => {
+                entry.to_raw_data()
+            }
+            Command::ClearSchedule(slot) => {
+                slot.iter().copied().collect()
             }
         }
     }
 
-    fn get_raw_data(&self) -> Vec<u8> {
+    pub(crate) fn get_raw_data(&self) -> Vec<u8> {
         let command_data = self.get_command_data();
         let mut raw_data = vec![COMMAND_START_BYTE, self.get_command_code(), command_data.len() as u8];
         raw_data.extend(command_data);
@@ -57,117 +99,557 @@ impl Command {
 
         raw_data
     }
+
+    /// Parses a single framed command, the way the firmware reads them. Returns `None` if the
+    /// frame is malformed or the command is unknown.
+    pub(crate) fn from_raw_data(raw_data: &[u8]) -> Option<Self> {
+        if raw_data.len() < 4 || raw_data[0] != COMMAND_START_BYTE || raw_data[raw_data.len() - 1] != COMMAND_END_BYTE {
+            return None;
+        }
+        let data = &raw_data[3..raw_data.len() - 1];
+        if data.len() != raw_data[2] as usize {
+            return None;
+        }
+
+        match raw_data[1] {
+            0x00 => { String::from_utf8(data.to_vec()).ok().map(Command::SetName) }
+            0x01 => { Some(Command::Echo(data.to_vec())) }
+            0x02 if data.len() == 3 => { Some(Command::SetLEDColor(HSVColor::from_raw_data([data[0], data[1], data[2]]))) }
+            0x03 if data.len() == 1 => { Some(Command::SetBrightness(f64::from(data[0]) / 255.0)) }
+            0x04 => { Some(Command::GetDeviceInfo) }
+            0x05 => { Animation::from_raw_data(data).ok().map(Command::SetAnimation) }
+            0x06 => { Some(Command::GetColorInfo) }
+            0x07 => { ScheduleEntry::from_raw_data(data).ok().map(Command::SetSchedule) }
+            0x08 => { Some(Command::ClearSchedule(data.first().copied())) }
+            _ => { None }
+        }
+    }
 }
 
-pub(crate) struct HomeLightPeripheral {
-    rx: Option<mpsc::UnboundedReceiver<Command>>,
-    raw_peripheral: Peripheral,
+/// Where a light's connection stands, as tracked by its supervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ConnectionState {
+    /// Trying to connect, for the first time or after the connection dropped.
+    Connecting,
+    /// Connected, but not listening to the light yet.
+    Connected,
+    /// Connected and listening to the light.
+    Subscribed,
+    /// Subscribed, but writes are failing or the light stopped answering.
+    Degraded,
+    /// Connecting keeps failing, attempts go on at the maximum backoff.
+    Lost,
+}
+
+impl ConnectionState {
+    pub(crate) fn is_connected(self) -> bool {
+        matches!(self, ConnectionState::Connected | ConnectionState::Subscribed | ConnectionState::Degraded)
+    }
+}
+
+pub(crate) struct HomeLightPeripheral<T: Transport> {
+    /// Shared with the command task so it can be restarted with the same queue.
+    command_rx: Arc<AsyncMutex<CommandReceiver>>,
+    /// Kept to close the queue on shutdown.
+    command_queue: CommandQueue,
+    transport: T,
+    timing: TimingConfig,
+    link: Arc<Link>,
+}
+
+/// What the supervisor, the command task and the notification task know about the connection.
+struct Link {
+    address: String,
+    state_tx: watch::Sender<ConnectionState>,
+    /// When a request the light always answers was written, until anything is heard back.
+    awaiting_answer_since: Mutex<Option<Instant>>,
+    /// Tells the supervisor to drop the connection and start over.
+    reconnect: Notify,
+}
+
+/// The tasks a supervisor looks after, kept outside the supervision loop so they can be shut
+/// down whenever it's stopped.
+struct Tasks {
+    command_handle: JoinHandle<()>,
     notification_handle: Option<JoinHandle<()>>,
-    command_handle: Option<JoinHandle<()>>,
 }
 
-impl HomeLightPeripheral {
-    pub fn new(raw_peripheral: Peripheral) -> (Self, mpsc::UnboundedSender<Command>) {
+/// Stops a running supervisor, which writes what's still queued and disconnects from the light.
+pub(crate) struct Supervisor {
+    address: String,
+    stop_tx: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+impl<T: Transport> HomeLightPeripheral<T> {
+    pub fn new(transport: T, timing: TimingConfig) -> (Self, CommandQueue) {
+        let (command_queue, command_rx) = queue::command_queue(queue::COMMAND_QUEUE_SIZE);
+        let link = Arc::new(Link {
+            address: transport.address(),
+            state_tx: watch::channel(ConnectionState::Connecting).0,
+            awaiting_answer_since: Mutex::new(None),
+            reconnect: Notify::new(),
+        });
+        let command_rx = Arc::new(AsyncMutex::new(command_rx));
+
+        (HomeLightPeripheral { command_rx, command_queue: command_queue.clone(), transport, timing, link }, command_queue)
+    }
+
+    /// Follows the state of the connection to the light, starting out connecting.
+    pub fn connection_status(&self) -> watch::Receiver<ConnectionState> {
+        self.link.state_tx.subscribe()
+    }
+
+    /// Starts supervising the connection in the background, messages from the light are sent to
+    /// the returned receiver until the supervisor is stopped.
+    pub fn start_listening(&mut self) -> (mpsc::UnboundedReceiver<decoder::HomeLightMessage>, Supervisor) {
         let (tx, rx) = mpsc::unbounded_channel();
-        let notification_handle = None;
-        let command_handle = None;
-
-        (HomeLightPeripheral { rx: Some(rx), raw_peripheral, notification_handle, command_handle }, tx)
-    }
-
-    pub async fn start_listening(&mut self) -> btleplug::Result<mpsc::UnboundedReceiver<decoder::HomeLightMessage>> {
-        while Self::connect_if_needed(&self.raw_peripheral).await == false {}
-        let chars = self.raw_peripheral.discover_characteristics().await?;
-        let is_connected = self.raw_peripheral.is_connected().await?;
-        if is_connected {
-            for characteristic in chars.into_iter() {
-                // Subscribe to notifications from the characteristic with the selected
-                // UUID.
-                if characteristic.uuid == NOTIFY_CHARACTERISTIC_UUID
-                    && characteristic.properties.contains(CharPropFlags::NOTIFY)
-                {
-                    println!("Subscribing to characteristic {:?}", characteristic.uuid);
-                    self.raw_peripheral.subscribe(&characteristic).await?;
-                    println!("Subscribed");
-
-                    let (tx, rx) = mpsc::unbounded_channel();
-                    let decoder = decoder::HomeLightDecoder::new(tx);
-                    let notification_peripheral = self.raw_peripheral.clone();
-                    self.notification_handle = Some(tokio::spawn(async move {
-                        Self::process_notifications(&notification_peripheral, decoder).await.unwrap();
-                        ()
-                    }));
-                    let command_peripheral = self.raw_peripheral.clone();
-                    let command_characteristic = characteristic.clone();
-                    let mut command_rx = self.rx.take().unwrap();
-                    self.command_handle = Some(tokio::spawn(async move {
-                        while let Some(command) = command_rx.recv().await {
-                            while HomeLightPeripheral::send_command(command_peripheral.clone(), &command_characteristic, command.clone()).await.is_err() {
-                                //println!("Error sending command: {:?}", error);
-                                let _ = command_peripheral.clone().disconnect().await;
-                                sleep(Duration::from_millis(500)).await;
-                            }
-                            sleep(Duration::from_millis(100)).await;
-                        }
-                        ()
-                    }));
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let handle = tokio::spawn(Self::supervise(
+            self.transport.clone(),
+            self.timing.clone(),
+            self.link.clone(),
+            self.command_rx.clone(),
+            self.command_queue.clone(),
+            tx,
+            stop_rx,
+        ));
+
+        (rx, Supervisor { address: self.link.address.clone(), stop_tx, handle })
+    }
+}
+
+// MARK: - Supervision
+
+impl<T: Transport> HomeLightPeripheral<T> {
+    /// Keeps the light connected and listened to until it's told to stop, then shuts it down.
+    #[allow(clippy::too_many_arguments)]
+    async fn supervise(
+        transport: T,
+        timing: TimingConfig,
+        link: Arc<Link>,
+        command_rx: Arc<AsyncMutex<CommandReceiver>>,
+        command_queue: CommandQueue,
+        message_tx: mpsc::UnboundedSender<decoder::HomeLightMessage>,
+        mut stop_rx: watch::Receiver<bool>,
+    ) {
+        let mut tasks = Tasks {
+            command_handle: tokio::spawn(Self::process_commands(transport.clone(), timing.clone(), link.clone(), command_rx.clone())),
+            notification_handle: None,
+        };
+        select! {
+            _ = Self::keep_connected(&transport, &timing, &link, &command_rx, &message_tx, &mut tasks) => {}
+            _ = stop_rx.wait_for(|stop| *stop) => {}
+        }
+
+        Self::shut_down(&transport, &timing, &link, &command_queue, tasks).await;
+    }
+
+    /// Connects with backoff, subscribes to the light's notifications, then watches for the
+    /// stream closing, the link dropping, failing writes or a dead command task. Anything but the
+    /// latter starts over with a fresh connection.
+    async fn keep_connected(transport: &T, timing: &TimingConfig, link: &Arc<Link>, command_rx: &Arc<AsyncMutex<CommandReceiver>>, message_tx: &mpsc::UnboundedSender<decoder::HomeLightMessage>, tasks: &mut Tasks) {
+        loop {
+            Self::connect(transport, timing, link).await;
+            let notification_stream = match transport.notifications().await {
+                Ok(notification_stream) => { notification_stream }
+                Err(err) => {
+                    eprintln!("Error subscribing to {}, reconnecting: {}", link.address, err);
+                    let _ = transport.disconnect().await;
+                    link.set_state(ConnectionState::Connecting);
+                    sleep(timing.reconnect_backoff_min()).await;
+                    continue;
+                }
+            };
+            let decoder = decoder::HomeLightDecoder::new(message_tx.clone());
+            let notification_handle = tasks.notification_handle.insert(tokio::spawn(Self::process_notifications(notification_stream, decoder, link.clone())));
+            link.set_state(ConnectionState::Subscribed);
 
-                    return Ok(rx);
+            let mut health_check = interval(timing.health_check_interval());
+            loop {
+                select! {
+                    _ = &mut *notification_handle => {
+                        eprintln!("Notification stream for {} closed, reconnecting", link.address);
+                        break;
+                    }
+                    _ = link.reconnect.notified() => {
+                        eprintln!("Writes to {} are failing, reconnecting", link.address);
+                        break;
+                    }
+                    result = &mut tasks.command_handle => {
+                        if let Err(err) = result {
+                            eprintln!("Command task for {} died, restarting it: {}", link.address, err);
+                        }
+                        tasks.command_handle = tokio::spawn(Self::process_commands(transport.clone(), timing.clone(), link.clone(), command_rx.clone()));
+                    }
+                    _ = health_check.tick() => {
+                        if !transport.is_connected().await.unwrap_or(false) {
+                            eprintln!("Lost connection to {}, reconnecting", link.address);
+                            break;
+                        }
+                        link.check_answers(timing.device_info_timeout());
+                    }
                 }
             }
+
+            notification_handle.abort();
+            let _ = transport.disconnect().await;
+            link.set_state(ConnectionState::Connecting);
+            // Give the link a moment to settle instead of reconnecting right away.
+            sleep(timing.reconnect_backoff_min()).await;
+        }
+    }
+
+    /// Turns new commands away and writes the queued ones while the light is still listened to,
+    /// for at most `command_timeout`, cancelling whatever is left. Then stops listening and
+    /// disconnects.
+    async fn shut_down(transport: &T, timing: &TimingConfig, link: &Link, command_queue: &CommandQueue, mut tasks: Tasks) {
+        println!("Shutting down {}", link.address);
+        command_queue.close();
+        let is_listening = matches!(*link.state_tx.borrow(), ConnectionState::Subscribed | ConnectionState::Degraded);
+        if is_listening && timeout(timing.command_timeout(), &mut tasks.command_handle).await.is_err() {
+            eprintln!("Cancelling commands still queued for {}", link.address);
+        }
+        // Dropping the receiver along with the task cancels anything left in the queue.
+        tasks.command_handle.abort();
+        if let Some(notification_handle) = tasks.notification_handle {
+            notification_handle.abort();
         }
 
-        return Err(btleplug::Error::NotSupported(String::from("Couldn't start listening to peripheral notifications")));
+        if let Err(err) = transport.unsubscribe().await {
+            eprintln!("Error unsubscribing from {}: {}", link.address, err);
+        }
+        if let Err(err) = transport.disconnect().await {
+            eprintln!("Error disconnecting from {}: {}", link.address, err);
+        }
+        println!("Disconnected from {}", link.address);
     }
 
-    async fn connect_if_needed(peripheral: &Peripheral) -> bool {
+    /// Connects with exponential backoff, the light is considered lost once the backoff has
+    /// reached its maximum. The transport is asked to connect even if its link is already up, it
+    /// still has to get ready to exchange frames.
+    async fn connect(transport: &T, timing: &TimingConfig, link: &Link) {
         use std::cmp;
 
-        let max_sleep_duration = 5_000;
-        let mut sleep_duration = 100;
+        let max_sleep_duration = timing.reconnect_backoff_max();
+        let mut sleep_duration = timing.reconnect_backoff_min();
 
-        while !peripheral.is_connected().await.unwrap_or(false) {
-            runner::LIGHT_STATE_REQUEST_IN_FLIGHT.store(false, Ordering::Relaxed);
-            let address = peripheral.address().to_string();
-            if let Err(err) = async_process::Command::new("sudo").arg("hcitool").arg("lecc").arg(&address).status().await {
-                eprintln!("Error connecting to peripheral through hcitool: {}", err);
+        loop {
+            match transport.connect().await {
+                Ok(()) => { break }
+                Err(err) => {
+                    eprintln!("Error connecting to {}, retrying: {}", link.address, err);
+                }
             }
-            if let Err(err) = peripheral.connect().await {
-                eprintln!("Error connecting to peripheral, retrying: {}", err);
+            if sleep_duration >= max_sleep_duration {
+                link.set_state(ConnectionState::Lost);
             }
-            sleep(Duration::from_millis(sleep_duration)).await;
+            sleep(sleep_duration).await;
             sleep_duration = cmp::min(sleep_duration * 2, max_sleep_duration);
         }
 
-        peripheral.is_connected().await.unwrap_or(false)
+        link.set_state(ConnectionState::Connected);
     }
 
-    async fn process_notifications(peripheral: &Peripheral, mut decoder: decoder::HomeLightDecoder) -> btleplug::Result<()> 
-    {
-        let mut notification_stream = peripheral.notifications().await?;
-        // Process while the BLE connection is not broken or stopped.
+    async fn process_notifications(mut notification_stream: transport::NotificationStream, mut decoder: decoder::HomeLightDecoder, link: Arc<Link>) {
+        // Process while the connection is not broken or stopped.
         while let Some(data) = notification_stream.next().await {
-            if data.uuid == NOTIFY_CHARACTERISTIC_UUID {
-                decoder.consume_data_packet(&data.value);
-            }
+            link.heard_back();
+            decoder.consume_data_packet(&data);
         }
+    }
+}
 
-        eprintln!("--- !!Notification Stream Closed!! ---");
+impl Link {
+    fn set_state(&self, state: ConnectionState) {
+        self.state_tx.send_if_modified(|current| std::mem::replace(current, state) != state);
+    }
+
+    /// Moves between subscribed and degraded, other states are left to the supervisor.
+    fn set_degraded(&self, is_degraded: bool) {
+        let (from, to) = if is_degraded {
+            (ConnectionState::Subscribed, ConnectionState::Degraded)
+        } else {
+            (ConnectionState::Degraded, ConnectionState::Subscribed)
+        };
+        self.state_tx.send_if_modified(|current| {
+            if *current == from {
+                *current = to;
+                true
+            } else {
+                false
+            }
+        });
+    }
 
-        Ok(())
+    fn expect_answer(&self) {
+        self.awaiting_answer_since.lock().unwrap().get_or_insert_with(Instant::now);
+    }
+
+    fn heard_back(&self) {
+        *self.awaiting_answer_since.lock().unwrap() = None;
+        self.set_degraded(false);
+    }
+
+    /// Marks the light degraded if it hasn't answered a request within `answer_timeout`.
+    fn check_answers(&self, answer_timeout: Duration) {
+        let awaiting_answer_since = *self.awaiting_answer_since.lock().unwrap();
+        if let Some(since) = awaiting_answer_since {
+            if since.elapsed() >= answer_timeout {
+                self.set_degraded(true);
+            }
+        }
+    }
+}
+
+impl Supervisor {
+    /// Tells the supervisor to shut the light down and waits up to `deadline` for it, after which
+    /// it's cut off.
+    pub async fn stop(mut self, deadline: Duration) {
+        self.stop_tx.send_replace(true);
+        if timeout(deadline, &mut self.handle).await.is_err() {
+            eprintln!("{} didn't shut down within {:?}, cutting it off", self.address, deadline);
+            self.handle.abort();
+        }
     }
 }
 
 // MARK: - Command Handling
 
-impl HomeLightPeripheral {
-    async fn send_command(peripheral: Peripheral, characteristic: &Characteristic, command: Command) -> btleplug::Result<()> {
-        let command_data = command.get_raw_data();
-        if peripheral.is_connected().await? == false {
-            while Self::connect_if_needed(&peripheral).await == false {}
+impl<T: Transport> HomeLightPeripheral<T> {
+    /// Writes queued commands once the light is connected, failed writes are reported to the
+    /// supervisor and retried up to `command_retries` times.
+    async fn process_commands(transport: T, timing: TimingConfig, link: Arc<Link>, command_rx: Arc<AsyncMutex<CommandReceiver>>) {
+        let mut command_rx = command_rx.lock().await;
+        let mut state_rx = link.state_tx.subscribe();
+        while let Some(queued) = command_rx.pop().await {
+            let mut outcome = CommandOutcome::Dropped(DropReason::WriteFailed);
+            for _ in 0..=timing.command_retries {
+                // Answers written before the light is listened to would be lost. The supervisor
+                // outlives this task, so the state can't stop changing.
+                let _ = state_rx.wait_for(|state| matches!(state, ConnectionState::Subscribed | ConnectionState::Degraded)).await;
+                match Self::send_command(&transport, &queued.command).await {
+                    Ok(()) => {
+                        if matches!(queued.command, Command::GetDeviceInfo) {
+                            link.expect_answer();
+                        }
+                        outcome = CommandOutcome::Sent;
+                        break;
+                    }
+                    Err(err) => {
+                        eprintln!("Error sending command to {}, retrying: {}", link.address, err);
+                        link.set_degraded(true);
+                        // Stored if the supervisor isn't waiting right now, so the request isn't lost.
+                        link.reconnect.notify_one();
+                        sleep(timing.command_retry_delay()).await;
+                    }
+                }
+            }
+            if outcome != CommandOutcome::Sent {
+                eprintln!("Dropping command after {} attempts: {:?}", timing.command_retries + 1, queued.command);
+            }
+            queued.complete(outcome);
+            sleep(timing.command_interval()).await;
         }
-        println!("Peripheral Connection State: {:?}", peripheral.is_connected().await?);
+    }
+
+    async fn send_command(transport: &T, command: &Command) -> transport::Result<()> {
+        let command_data = command.get_raw_data();
         println!("Sending Command Data: {:?}", command_data);
-        timeout(Duration::from_millis(2_000), peripheral.write(characteristic, &command_data, WriteType::WithoutResponse)).await.map_err(|err| btleplug::Error::Other(Box::new(err))).and_then(|n| n)
+        let write_timeout = Duration::from_millis(2_000);
+        timeout(write_timeout, transport.write_frame(&command_data)).await.map_err(|_| transport::Error::TimedOut(write_timeout)).and_then(|n| n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::{Keyframe, LoopMode};
+    use crate::simulator::{SimulatedLight, SimulatedTransport};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A light the system already connected to, e.g. BlueZ keeping the link up across restarts.
+    #[derive(Clone)]
+    struct AlreadyConnectedTransport {
+        simulated: SimulatedTransport,
+        connect_count: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for AlreadyConnectedTransport {
+        fn address(&self) -> String {
+            self.simulated.address()
+        }
+
+        async fn connect(&self) -> transport::Result<()> {
+            self.connect_count.fetch_add(1, Ordering::SeqCst);
+            self.simulated.connect().await
+        }
+
+        async fn disconnect(&self) -> transport::Result<()> {
+            self.simulated.disconnect().await
+        }
+
+        async fn is_connected(&self) -> transport::Result<bool> {
+            Ok(true)
+        }
+
+        async fn write_frame(&self, frame: &[u8]) -> transport::Result<()> {
+            self.simulated.write_frame(frame).await
+        }
+
+        async fn notifications(&self) -> transport::Result<transport::NotificationStream> {
+            self.simulated.notifications().await
+        }
+    }
+
+    /// Strips the framing from a raw command, checking it along the way.
+    fn unframe(raw_data: &[u8]) -> (u8, Vec<u8>) {
+        assert_eq!(raw_data.first(), Some(&COMMAND_START_BYTE));
+        assert_eq!(raw_data.last(), Some(&COMMAND_END_BYTE));
+        let length = raw_data[2] as usize;
+        assert_eq!(raw_data.len(), length + 4);
+
+        (raw_data[1], raw_data[3..3 + length].to_vec())
+    }
+
+    fn red() -> HSVColor {
+        HSVColor { h: 0.0, s: 1.0, v: 1.0 }
+    }
+
+    #[test]
+    fn commands_without_data_are_framed() {
+        assert_eq!(Command::GetDeviceInfo.get_raw_data(), vec![0xFE, 0x04, 0x00, 0xFF]);
+        assert_eq!(Command::GetColorInfo.get_raw_data(), vec![0xFE, 0x06, 0x00, 0xFF]);
+        assert_eq!(Command::ClearSchedule(None).get_raw_data(), vec![0xFE, 0x08, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn every_command_parses_back() {
+        let commands = vec![
+            Command::SetName(String::from("Hallway")),
+            Command::Echo(vec![1, 2, 3]),
+            Command::SetLEDColor(HSVColor { h: 240.0, s: 0.25, v: 0.75 }),
+            Command::SetBrightness(0.0),
+            Command::GetDeviceInfo,
+            Command::SetAnimation(Animation { loop_mode: LoopMode::Once, keyframes: vec![Keyframe { color: red(), duration_ms: 5 }] }),
+            Command::GetColorInfo,
+            Command::SetSchedule(ScheduleEntry { slot: 0, days: 0x7F, hour: 22, minute: 0, is_on: false, color: red() }),
+            Command::ClearSchedule(Some(1)),
+            Command::ClearSchedule(None),
+        ];
+        for command in commands.iter() {
+            let raw_data = command.get_raw_data();
+            let parsed = Command::from_raw_data(&raw_data).unwrap_or_else(|| panic!("Couldn't parse {:?}", command));
+            assert_eq!(parsed.get_raw_data(), raw_data);
+        }
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        assert!(Command::from_raw_data(&[0xFE, 0x04, 0x00]).is_none());
+        assert!(Command::from_raw_data(&[0xFE, 0x04, 0x01, 0xFF]).is_none());
+        assert!(Command::from_raw_data(&[0x00, 0x04, 0x00, 0xFF]).is_none());
+        assert!(Command::from_raw_data(&[0xFE, 0x02, 0x01, 0x00, 0xFF]).is_none());
+        assert!(Command::from_raw_data(&[0xFE, 0x42, 0x00, 0xFF]).is_none());
+    }
+
+    #[test]
+    fn set_name_round_trips() {
+        let (code, data) = unframe(&Command::SetName(String::from("Kitchen")).get_raw_data());
+        assert_eq!(code, 0x00);
+        assert_eq!(String::from_utf8(data).unwrap(), "Kitchen");
+    }
+
+    #[test]
+    fn set_name_truncates_on_a_char_boundary() {
+        let name = "é".repeat(MAX_NAME_LENGTH);
+        let (_, data) = unframe(&Command::SetName(name).get_raw_data());
+        assert_eq!(data.len(), MAX_NAME_LENGTH / 2 * 2);
+        assert!(String::from_utf8(data).is_ok());
+    }
+
+    #[test]
+    fn echo_round_trips() {
+        let (code, data) = unframe(&Command::Echo(vec![0xFE, 0x00, 0xFF]).get_raw_data());
+        assert_eq!(code, 0x01);
+        assert_eq!(data, vec![0xFE, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn set_led_color_round_trips() {
+        let color = HSVColor { h: 180.0, s: 0.5, v: 1.0 };
+        let (code, data) = unframe(&Command::SetLEDColor(color).get_raw_data());
+        assert_eq!(code, 0x02);
+        assert_eq!(data, vec![128, 128, 255]);
+
+        let decoded = HSVColor::from_raw_data([data[0], data[1], data[2]]);
+        assert!((decoded.h - 180.0).abs() < 1.0);
+        assert!((decoded.s - 0.5).abs() < 0.01);
+        assert!((decoded.v - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn set_brightness_is_clamped() {
+        assert_eq!(Command::SetBrightness(0.5).get_raw_data(), vec![0xFE, 0x03, 0x01, 128, 0xFF]);
+        assert_eq!(Command::SetBrightness(2.0).get_raw_data(), vec![0xFE, 0x03, 0x01, 255, 0xFF]);
+        assert_eq!(Command::SetBrightness(-1.0).get_raw_data(), vec![0xFE, 0x03, 0x01, 0, 0xFF]);
+    }
+
+    #[test]
+    fn set_animation_round_trips() {
+        let animation = Animation {
+            loop_mode: LoopMode::PingPong,
+            keyframes: vec![
+                Keyframe { color: red(), duration_ms: 1_000 },
+                Keyframe { color: HSVColor { h: 120.0, s: 1.0, v: 0.5 }, duration_ms: 300 },
+            ],
+        };
+        let (code, data) = unframe(&Command::SetAnimation(animation).get_raw_data());
+        assert_eq!(code, 0x05);
+        assert_eq!(data, vec![
+            0x02, 2,
+            0, 255, 255, 0x03, 0xE8,
+            85, 255, 128, 0x01, 0x2C,
+        ]);
+    }
+
+    #[test]
+    fn set_animation_limits_keyframes_to_what_the_light_reports() {
+        let animation = Animation {
+            loop_mode: LoopMode::Repeat,
+            keyframes: vec![Keyframe { color: red(), duration_ms: 10 }; 60],
+        };
+        let (_, data) = unframe(&Command::SetAnimation(animation).get_raw_data());
+        assert_eq!(data[1] as usize, MAX_KEYFRAMES);
+        assert_eq!(data.len(), 2 + MAX_KEYFRAMES * 5);
+    }
+
+    #[test]
+    fn schedule_commands_round_trip() {
+        let entry = ScheduleEntry { slot: 3, days: 0b0111_1110, hour: 7, minute: 30, is_on: true, color: red() };
+        let (code, data) = unframe(&Command::SetSchedule(entry).get_raw_data());
+        assert_eq!(code, 0x07);
+        assert_eq!(data, vec![3, 0b0111_1110, 7, 30, 1, 0, 255, 255]);
+
+        let (code, data) = unframe(&Command::ClearSchedule(Some(3)).get_raw_data());
+        assert_eq!(code, 0x08);
+        assert_eq!(data, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn a_link_thats_already_up_is_still_prepared() {
+        let connect_count = Arc::new(AtomicUsize::new(0));
+        let transport = AlreadyConnectedTransport {
+            simulated: SimulatedTransport::new("simulated-0", SimulatedLight::new("Simulated Light 0")),
+            connect_count: connect_count.clone(),
+        };
+        let (mut peripheral, _command_queue) = HomeLightPeripheral::new(transport, TimingConfig::default());
+        let mut status = peripheral.connection_status();
+        let (_message_rx, _supervisor) = peripheral.start_listening();
+
+        timeout(Duration::from_secs(2), status.wait_for(|state| *state == ConnectionState::Subscribed)).await.unwrap().unwrap();
+        assert_eq!(connect_count.load(Ordering::SeqCst), 1);
     }
 }
@@ -0,0 +1,253 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem::{self, Discriminant};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::{oneshot, Notify};
+
+use crate::peripheral::Command;
+
+/// How many commands can wait for a single light before new ones are turned away.
+pub(crate) const COMMAND_QUEUE_SIZE: usize = 32;
+
+/// What became of a queued command.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CommandOutcome {
+    /// Written to the light.
+    Sent,
+    /// Replaced by a newer command of the same kind before it was written.
+    Superseded,
+    Dropped(DropReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DropReason {
+    /// Too many commands are already waiting.
+    QueueFull,
+    /// Nothing is taking commands for the light anymore.
+    Closed,
+    /// Writing failed on every attempt.
+    WriteFailed,
+}
+
+/// The sending side of a light's command queue, clones add to the same queue.
+///
+/// Unlike a plain channel the queue only keeps what still matters: a color or brightness change
+/// replaces one of the same kind that hasn't been written yet. The replacement goes to the back
+/// like any new command, so it's still written after everything queued before it. Requests for device info are
+/// shared by `RunState`, which has to know about every one the light will answer.
+#[derive(Clone)]
+pub(crate) struct CommandQueue {
+    shared: Arc<Shared>,
+}
+
+/// The receiving side of a light's command queue. Dropping it closes the queue.
+pub(crate) struct CommandReceiver {
+    shared: Arc<Shared>,
+}
+
+/// A command taken off the queue, `complete` tells everyone waiting on it what happened.
+pub(crate) struct QueuedCommand {
+    pub command: Command,
+    waiters: Vec<oneshot::Sender<CommandOutcome>>,
+}
+
+/// Resolves to the outcome of a queued command.
+pub(crate) struct CommandTicket {
+    outcome_rx: oneshot::Receiver<CommandOutcome>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+struct State {
+    commands: VecDeque<QueuedCommand>,
+    capacity: usize,
+    is_closed: bool,
+}
+
+pub(crate) fn command_queue(capacity: usize) -> (CommandQueue, CommandReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State { commands: VecDeque::new(), capacity, is_closed: false }),
+        notify: Notify::new(),
+    });
+
+    (CommandQueue { shared: shared.clone() }, CommandReceiver { shared })
+}
+
+impl CommandQueue {
+    /// Queues `command`, failing right away if it can't be queued at all.
+    pub(crate) fn push(&self, command: Command) -> Result<CommandTicket, DropReason> {
+        let (outcome_tx, outcome_rx) = oneshot::channel();
+        let mut state = self.shared.state.lock().unwrap();
+        if state.is_closed {
+            return Err(DropReason::Closed);
+        }
+
+        let command_kind = kind(&command);
+        let existing = command_kind.and_then(|command_kind| {
+            state.commands.iter().position(|queued| kind(&queued.command) == Some(command_kind))
+        });
+        match existing {
+            Some(index) => {
+                // Moving it to the back keeps it after e.g. a power change queued since.
+                if let Some(queued) = state.commands.remove(index) {
+                    queued.complete(CommandOutcome::Superseded);
+                }
+                state.commands.push_back(QueuedCommand { command, waiters: vec![outcome_tx] });
+            }
+            None => {
+                if state.commands.len() >= state.capacity {
+                    return Err(DropReason::QueueFull);
+                }
+                state.commands.push_back(QueuedCommand { command, waiters: vec![outcome_tx] });
+                self.shared.notify.notify_one();
+            }
+        }
+
+        Ok(CommandTicket { outcome_rx })
+    }
+
+    /// Turns new commands away, the ones already queued can still be taken off the queue.
+    pub(crate) fn close(&self) {
+        self.shared.state.lock().unwrap().is_closed = true;
+        self.shared.notify.notify_waiters();
+    }
+}
+
+impl CommandReceiver {
+    /// Waits for the next command, `None` once the queue is closed and empty.
+    pub(crate) async fn pop(&mut self) -> Option<QueuedCommand> {
+        loop {
+            let notified = self.shared.notify.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(queued) = state.commands.pop_front() {
+                    return Some(queued);
+                }
+                if state.is_closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for CommandReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.is_closed = true;
+        for queued in state.commands.drain(..) {
+            queued.complete(CommandOutcome::Dropped(DropReason::Closed));
+        }
+    }
+}
+
+impl QueuedCommand {
+    pub(crate) fn complete(self, outcome: CommandOutcome) {
+        for waiter in self.waiters {
+            // Nobody waiting on the outcome is fine.
+            let _ = waiter.send(outcome);
+        }
+    }
+}
+
+impl CommandTicket {
+    pub(crate) async fn outcome(self) -> CommandOutcome {
+        // The queue only goes away without completing a command when it's closed.
+        self.outcome_rx.await.unwrap_or(CommandOutcome::Dropped(DropReason::Closed))
+    }
+}
+
+/// Commands of the same kind replace each other while queued, others are kept as they are.
+fn kind(command: &Command) -> Option<Discriminant<Command>> {
+    match command {
+        Command::SetLEDColor(_) | Command::SetBrightness(_) => { Some(mem::discriminant(command)) }
+        _ => { None }
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DropReason::QueueFull => { write!(f, "Too many commands are waiting for the light") }
+            DropReason::Closed => { write!(f, "The light has stopped accepting commands") }
+            DropReason::WriteFailed => { write!(f, "The command couldn't be written to the light") }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::HSVColor;
+
+    fn queued_count(queue: &CommandQueue) -> usize {
+        queue.shared.state.lock().unwrap().commands.len()
+    }
+
+    fn color(h: f64) -> Command {
+        Command::SetLEDColor(HSVColor { h, s: 1.0, v: 1.0 })
+    }
+
+    #[tokio::test]
+    async fn newer_colors_replace_queued_ones_at_the_back() {
+        let (queue, mut receiver) = command_queue(COMMAND_QUEUE_SIZE);
+        let first = queue.push(color(0.0)).unwrap();
+        queue.push(Command::SetBrightness(0.0)).unwrap();
+        let last = queue.push(color(120.0)).unwrap();
+        assert_eq!(queued_count(&queue), 2);
+        assert_eq!(first.outcome().await, CommandOutcome::Superseded);
+
+        // Written in the order they were last asked for.
+        assert!(matches!(receiver.pop().await.unwrap().command, Command::SetBrightness(_)));
+        let queued = receiver.pop().await.unwrap();
+        assert!(matches!(queued.command, Command::SetLEDColor(HSVColor { h, .. }) if h == 120.0));
+        queued.complete(CommandOutcome::Sent);
+        assert_eq!(last.outcome().await, CommandOutcome::Sent);
+    }
+
+    #[tokio::test]
+    async fn device_info_requests_are_all_sent() {
+        let (queue, mut receiver) = command_queue(COMMAND_QUEUE_SIZE);
+        let first = queue.push(Command::GetDeviceInfo).unwrap();
+        let second = queue.push(Command::GetDeviceInfo).unwrap();
+        assert_eq!(queued_count(&queue), 2);
+
+        receiver.pop().await.unwrap().complete(CommandOutcome::Sent);
+        receiver.pop().await.unwrap().complete(CommandOutcome::Sent);
+        assert_eq!(first.outcome().await, CommandOutcome::Sent);
+        assert_eq!(second.outcome().await, CommandOutcome::Sent);
+    }
+
+    #[tokio::test]
+    async fn full_and_closed_queues_turn_commands_away() {
+        let (queue, receiver) = command_queue(1);
+        let first = queue.push(color(0.0)).unwrap();
+        // Replacing a queued command doesn't take up more room.
+        let second = queue.push(color(120.0)).unwrap();
+        assert_eq!(queue.push(Command::GetColorInfo).err(), Some(DropReason::QueueFull));
+        assert_eq!(first.outcome().await, CommandOutcome::Superseded);
+
+        drop(receiver);
+        assert_eq!(second.outcome().await, CommandOutcome::Dropped(DropReason::Closed));
+        assert_eq!(queue.push(Command::GetDeviceInfo).err(), Some(DropReason::Closed));
+    }
+
+    #[tokio::test]
+    async fn a_closed_queue_is_drained_first() {
+        let (queue, mut receiver) = command_queue(COMMAND_QUEUE_SIZE);
+        queue.push(Command::GetDeviceInfo).unwrap();
+        queue.close();
+        assert_eq!(queue.push(Command::GetColorInfo).err(), Some(DropReason::Closed));
+
+        assert!(matches!(receiver.pop().await.unwrap().command, Command::GetDeviceInfo));
+        assert!(receiver.pop().await.is_none());
+    }
+}
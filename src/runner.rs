@@ -1,262 +1,956 @@
 
 use rocket::State;
+use rocket::http::Status;
+use serde::Serialize;
 
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
-use btleplug::platform::Peripheral;
 
-use tokio::sync::mpsc::UnboundedSender;
+use futures::future::join_all;
+use tokio::sync::{broadcast, watch};
 
-use rocket::tokio::time::{sleep, Duration};
+use rocket::tokio::time::{timeout, Duration, Instant};
 
+use crate::config::TimingConfig;
 use crate::decoder::HomeLightMessageType;
-use crate::light::LightInfo;
-use crate::peripheral;
-
-const LIGHT_INFO_TTL: u128 = u128::MAX;
+use crate::light::{HSVColor, LightInfo};
+use crate::peripheral::{self, ConnectionState};
+use crate::queue::{CommandQueue, CommandTicket, DropReason};
+use crate::transport::Transport;
+
+/// A light the hub is talking to, cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Light {
+    pub run_state: RocketRunState,
+    pub command_queue: CommandQueue,
+}
 
+/// The lights served by the API. Clones share the same registry, so lights can be added or
+/// replaced from other tasks while the server is running.
+#[derive(Clone)]
 pub(crate) struct PeripheralState {
-    peripherals: Vec<(RocketRunState, RocketCommandChannel)>
-    //peripherals: Vec<Arc<Mutex<(RunState, Sender<peripheral::Command>)>>>
+    /// Kept in the order lights were first found in, for positional lookups, so it's a list
+    /// rather than a map. `add_light` keeps addresses unique.
+    lights: Arc<RwLock<Vec<Light>>>,
+    /// Changes to any light, lights are started with a clone of this.
+    events: broadcast::Sender<LightEvent>,
+}
+
+/// Something that changed about a light, as pushed to event stream listeners.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum LightEvent {
+    /// The light reported new state, or a command changed the cached state.
+    State { address: String, state: LightInfo },
+    /// The supervisor's view of the connection changed, `connected` is derived from `state`.
+    Connection { address: String, connected: bool, state: ConnectionState },
+}
+
+impl LightEvent {
+    pub(crate) fn address(&self) -> &str {
+        match self {
+            LightEvent::State { address, .. } | LightEvent::Connection { address, .. } => { address }
+        }
+    }
 }
 
 impl PeripheralState {
-    pub(crate) fn new(peripherals: Vec<(RocketRunState, RocketCommandChannel)>) -> Self {
+    pub(crate) fn new() -> Self {
         PeripheralState {
-            peripherals
+            lights: Arc::new(RwLock::new(Vec::new())),
+            events: broadcast::channel(LIGHT_EVENT_BUFFER_SIZE).0,
+        }
+    }
+
+    /// Where lights started for this registry should report their changes.
+    pub(crate) fn events(&self) -> broadcast::Sender<LightEvent> {
+        self.events.clone()
+    }
+
+    /// Adds a light to the registry. A light with the same address replaces the existing entry
+    /// and keeps its position.
+    pub(crate) fn add_light(&self, light: Light) {
+        let address = light.address();
+        let mut lights = self.lights.write().unwrap();
+        match lights.iter_mut().find(|existing| existing.address() == address) {
+            Some(existing) => { *existing = light }
+            None => { lights.push(light) }
+        }
+    }
+
+    /// Looks a light up by its address, the name it reports, or as a fallback its position in the
+    /// order lights were found in. Addresses and names are matched case-insensitively.
+    pub(crate) fn find(&self, id: &str) -> Option<Light> {
+        let lights = self.lights.read().unwrap();
+
+        let by_address = lights.iter().find(|light| light.address().eq_ignore_ascii_case(id));
+        let by_name = || lights.iter().find(|light| {
+            light.name().map(|name| name.eq_ignore_ascii_case(id)).unwrap_or(false)
+        });
+        // Kept so existing clients using positional routes keep working.
+        let by_index = || id.parse::<usize>().ok().and_then(|index| lights.get(index));
+
+        by_address.or_else(by_name).or_else(by_index).cloned()
+    }
+
+    /// Every light in the registry, in the order they were found in.
+    pub(crate) fn lights(&self) -> Vec<Light> {
+        self.lights.read().unwrap().clone()
+    }
+
+    /// Stops every light at once, each gets up to `deadline` to disconnect cleanly.
+    pub(crate) async fn shutdown(&self, deadline: Duration) {
+        let lights = self.lights();
+        join_all(lights.iter().map(|light| light.stop(deadline))).await;
+    }
+}
+
+impl Light {
+    pub(crate) fn address(&self) -> String {
+        self.run_state.lock().unwrap().address.clone()
+    }
+
+    /// The name the light last reported, if it has reported one.
+    pub(crate) fn name(&self) -> Option<String> {
+        self.run_state.lock().unwrap().light_info.as_ref().map(|(light_info, _)| light_info.name.clone())
+    }
+
+    /// The light info last reported and when it was received, in milliseconds since the epoch.
+    /// Doesn't ask the light for anything.
+    pub(crate) fn cached_light_info(&self) -> Option<(LightInfo, u128)> {
+        self.run_state.lock().unwrap().light_info.clone()
+    }
+
+    /// Queues a command for the light, the ticket tells whether it was sent in the end. Commands
+    /// that change the color or brightness cancel a running transition.
+    pub(crate) fn send(&self, command: peripheral::Command) -> Result<CommandTicket, DropReason> {
+        use peripheral::Command;
+
+        if matches!(command, Command::SetLEDColor(_) | Command::SetBrightness(_) | Command::SetAnimation(_)) {
+            self.cancel_transition();
+        }
+        self.command_queue.push(command)
+    }
+
+    /// Makes way for a new transition, cancelling the running one, and returns its id.
+    pub(crate) fn begin_transition(&self) -> u64 {
+        let mut run_state = self.run_state.lock().unwrap();
+        run_state.last_transition_id += 1;
+        run_state.transition = Some(run_state.last_transition_id);
+
+        run_state.last_transition_id
+    }
+
+    /// Queues a step of transition `id` and updates the cached state to match, unless the
+    /// transition was cancelled in the meantime. Checked under the same lock `send` cancels
+    /// under, so a step can't take the place of a newer command.
+    pub(crate) fn send_transition_step(&self, id: u64, color: HSVColor) -> Result<Option<CommandTicket>, DropReason> {
+        let mut run_state = self.run_state.lock().unwrap();
+        if run_state.transition != Some(id) {
+            return Ok(None);
+        }
+
+        let ticket = self.command_queue.push(peripheral::Command::SetLEDColor(color.clone()))?;
+        if let Some((light_info, _)) = &mut run_state.light_info {
+            light_info.color = color;
+            light_info.animation = None;
+            run_state.light_info_changed();
+        }
+
+        Ok(Some(ticket))
+    }
+
+    /// Marks transition `id` as done, if it's still the running one.
+    pub(crate) fn end_transition(&self, id: u64) {
+        let mut run_state = self.run_state.lock().unwrap();
+        if run_state.transition == Some(id) {
+            run_state.transition = None;
+        }
+    }
+
+    /// Stops a running transition where it is, returning whether there was one.
+    pub(crate) fn cancel_transition(&self) -> bool {
+        self.run_state.lock().unwrap().transition.take().is_some()
+    }
+
+    pub(crate) fn is_transitioning(&self) -> bool {
+        self.run_state.lock().unwrap().transition.is_some()
+    }
+
+    /// Turns the light on or off and updates the cached state to match.
+    pub(crate) fn set_power(&self, is_on: bool) -> Result<CommandTicket, DropReason> {
+        let ticket = self.send(peripheral::Command::SetBrightness(if is_on { 1.0 } else { 0.0 }))?;
+        self.update_light_info(|light_info| light_info.is_on = is_on);
+
+        Ok(ticket)
+    }
+
+    /// Sets a solid color, which also stops any animation, and updates the cached state to match.
+    pub(crate) fn set_color(&self, color: HSVColor) -> Result<CommandTicket, DropReason> {
+        let ticket = self.send(peripheral::Command::SetLEDColor(color.clone()))?;
+        self.update_light_info(|light_info| {
+            light_info.color = color;
+            light_info.animation = None;
+        });
+
+        Ok(ticket)
+    }
+
+    pub(crate) fn timing(&self) -> TimingConfig {
+        self.run_state.lock().unwrap().timing.clone()
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connection_state().is_connected()
+    }
+
+    pub(crate) fn connection_state(&self) -> ConnectionState {
+        self.run_state.lock().unwrap().connection_state
+    }
+
+    /// When anything was last heard from the light, in milliseconds since the epoch.
+    pub(crate) fn last_seen_ms(&self) -> Option<u128> {
+        self.run_state.lock().unwrap().last_seen_ms
+    }
+
+    /// Writes what's still queued and disconnects from the light, waiting up to `deadline` for it.
+    /// The light takes no more commands afterwards.
+    pub(crate) async fn stop(&self, deadline: Duration) {
+        let supervisor = self.run_state.lock().unwrap().supervisor.take();
+        if let Some(supervisor) = supervisor {
+            supervisor.stop(deadline).await;
+        }
+    }
+
+    fn update_light_info(&self, update: impl FnOnce(&mut LightInfo)) {
+        let mut run_state = self.run_state.lock().unwrap();
+        if let Some((light_info, _)) = &mut run_state.light_info {
+            update(light_info);
+            run_state.light_info_changed();
         }
     }
 }
 
 pub(crate) struct RunState {
+    /// Identifies the light on its transport, e.g. its BLE address.
+    address: String,
     light_info: Option<(LightInfo, u128)>,
+    /// How long `light_info` is served and how long to wait for the light to answer.
+    timing: TimingConfig,
+    /// GetDeviceInfo requests the light hasn't answered yet, oldest first. Everyone waiting on
+    /// the same answer shares a request.
+    device_info_requests: VecDeque<DeviceInfoRequest>,
+    last_request_id: u64,
+    /// Id of the latest request answered by the light.
+    answered_tx: watch::Sender<u64>,
+    connection_state: ConnectionState,
+    last_seen_ms: Option<u128>,
+    events: broadcast::Sender<LightEvent>,
+    /// Looks after the connection, taken when the light is stopped.
+    supervisor: Option<peripheral::Supervisor>,
+    /// Id of the running transition, cleared when it finishes or is cancelled.
+    transition: Option<u64>,
+    last_transition_id: u64,
+}
+
+/// The protocol doesn't tag messages, so the light is assumed to answer in order and each
+/// DeviceInfo it sends is taken as the answer to the oldest request.
+struct DeviceInfoRequest {
+    id: u64,
+    sent_at: Instant,
 }
 
 pub(crate) type RocketRunState = Arc<Mutex<RunState>>;
-pub(crate) type RocketCommandChannel = Arc<Mutex<UnboundedSender<peripheral::Command>>>;
 
-pub static LIGHT_STATE_REQUEST_IN_FLIGHT: AtomicBool = AtomicBool::new(false);
+/// How many events are buffered for a slow listener before old ones are dropped.
+const LIGHT_EVENT_BUFFER_SIZE: usize = 64;
+
+/// The light didn't report its state in time, or can't take commands at all.
+#[derive(Debug)]
+pub(crate) struct Unreachable {
+    pub address: String,
+    /// How long we waited for an answer.
+    pub waited: Duration,
+    /// The state the light last reported and how long ago, if it ever reported one.
+    pub last_known: Option<(LightInfo, Duration)>,
+}
+
+impl fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} didn't report its state within {:?}", self.address, self.waited)?;
+        if let Some((_, age)) = &self.last_known {
+            write!(f, ", last report was {:?} ago", age)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for Unreachable {}
 
-#[get("/<index>/light_state")]
-pub(crate) async fn light_state(index: usize, state: &State<PeripheralState>) -> String {
-    println!("Getting light state for index: {}", index);
-    let light_info = _get_latest_device_info(index, state, true).await;
+#[get("/<id>/light_state")]
+pub(crate) async fn light_state(id: &str, state: &State<PeripheralState>) -> Result<String, Status> {
+    println!("Getting light state for: {}", id);
+    let light = state.find(id).ok_or(Status::NotFound)?;
+    let light_info = legacy_light_info(get_device_info(&light, true).await)?;
 
-    format!("{:?}", light_info)
+    Ok(format!("{:?}", light_info))
 }
 
-#[get("/<index>/power_state")]
-pub(crate) async fn get_power_state(index: usize, state: &State<PeripheralState>) -> String {
-    let light_info = get_latest_device_info(index, state).await;
+#[get("/<id>/power_state")]
+pub(crate) async fn get_power_state(id: &str, state: &State<PeripheralState>) -> Result<String, Status> {
+    let light = state.find(id).ok_or(Status::NotFound)?;
+    let light_info = legacy_light_info(get_latest_device_info(&light).await)?;
 
-    format!("{}", if light_info.is_on { 1 } else { 0 })
+    Ok(format!("{}", if light_info.is_on { 1 } else { 0 }))
 }
 
-#[put("/<index>/power_state", data = "<value>")]
-pub(crate) async fn set_power_state(index: usize, value: String, state: &State<PeripheralState>) -> String {
+#[put("/<id>/power_state", data = "<value>")]
+pub(crate) async fn set_power_state(id: &str, value: String, state: &State<PeripheralState>) -> Result<String, Status> {
+    let light = state.find(id).ok_or(Status::NotFound)?;
     let new_value = match value.as_ref() {
-        "ON" => { Some(1.0) }
-        "OFF" => { Some(0.0) }
+        "ON" => { Some(true) }
+        "OFF" => { Some(false) }
         _ => { None }
     };
 
     if let Some(new_value) = new_value {
-        let command_channel = state.peripherals[index].1.lock().unwrap();
-        let _ = command_channel.send(peripheral::Command::SetBrightness(new_value));
-
-        if let Some((light_info, _)) = &mut state.peripherals[index].0.lock().unwrap().light_info {
-            light_info.is_on = new_value > 0.0;
-        }
+        let _ = light.set_power(new_value);
 
-        format!("Power state set")
+        Ok(String::from("Power state set"))
     } else {
-        format!("Unexpected Input, requires \"ON\" or \"OFF\"")
+        Ok(String::from("Unexpected Input, requires \"ON\" or \"OFF\""))
     }
 }
 
-#[get("/<index>/brightness")]
-pub(crate) async fn get_brightness(index: usize, state: &State<PeripheralState>) -> String {
-    let light_info = get_latest_device_info(index, state).await;
+#[get("/<id>/brightness")]
+pub(crate) async fn get_brightness(id: &str, state: &State<PeripheralState>) -> Result<String, Status> {
+    let light = state.find(id).ok_or(Status::NotFound)?;
+    let light_info = legacy_light_info(get_latest_device_info(&light).await)?;
 
     let normalized_brightness = light_info.color.v;
 
     let brightness = (normalized_brightness * 100.0).round().clamp(0.0, 100.0) as u8;
 
-    format!("{}", brightness)
+    Ok(format!("{}", brightness))
 }
 
-#[put("/<index>/brightness", data = "<value>")]
-pub(crate) async fn set_brightness(index: usize, value: String, state: &State<PeripheralState>) -> String {
+#[put("/<id>/brightness", data = "<value>")]
+pub(crate) async fn set_brightness(id: &str, value: String, state: &State<PeripheralState>) -> Result<String, Status> {
     // TODO: Add Error type for failure to parse
+    let light = state.find(id).ok_or(Status::NotFound)?;
 
     match value.parse::<u8>() {
-        Err(error) => { format!("Parsing Error: {}", error) }
+        Err(error) => { Ok(format!("Parsing Error: {}", error)) }
         Ok(new_value) => {
-            let light_info = get_latest_device_info(index, state).await;
+            let light_info = legacy_light_info(get_latest_device_info(&light).await)?;
 
             let mut new_color = light_info.color.clone();
             new_color.v = (new_value as f64 / 100.0).clamp(0.0, 1.0);
 
-            let command_channel = state.peripherals[index].1.lock().unwrap();
-            let _ = command_channel.send(peripheral::Command::SetLEDColor(new_color.clone()));
-            if let Some((light_info, _)) = &mut state.peripherals[index].0.lock().unwrap().light_info {
-                light_info.color.v = new_color.v;
-            }
+            let _ = light.set_color(new_color);
 
-            format!("Brightness Set")
+            Ok(String::from("Brightness Set"))
         }
     }
 }
 
-#[get("/<index>/hue")]
-pub(crate) async fn get_hue(index: usize, state: &State<PeripheralState>) -> String {
-    let light_info = get_latest_device_info(index, state).await;
+#[get("/<id>/hue")]
+pub(crate) async fn get_hue(id: &str, state: &State<PeripheralState>) -> Result<String, Status> {
+    let light = state.find(id).ok_or(Status::NotFound)?;
+    let light_info = legacy_light_info(get_latest_device_info(&light).await)?;
 
     let hue = light_info.color.h.round().clamp(0.0, 360.0) as u16;
 
-    format!("{}", hue)
+    Ok(format!("{}", hue))
 }
 
-#[put("/<index>/hue", data = "<value>")]
-pub(crate) async fn set_hue(index: usize, value: String, state: &State<PeripheralState>) -> String {
+#[put("/<id>/hue", data = "<value>")]
+pub(crate) async fn set_hue(id: &str, value: String, state: &State<PeripheralState>) -> Result<String, Status> {
     // TODO: Add Error type for failure to parse
-    
+    let light = state.find(id).ok_or(Status::NotFound)?;
+
     match value.parse::<f64>() {
-        Err(error) => { format!("Parsing Error: {}", error) }
+        Err(error) => { Ok(format!("Parsing Error: {}", error)) }
         Ok(new_value) => {
-            let light_info = get_latest_device_info(index, state).await;
+            let light_info = legacy_light_info(get_latest_device_info(&light).await)?;
 
             let mut new_color = light_info.color.clone();
             new_color.h = new_value.clamp(0.0, 360.0);
 
-            let command_channel = state.peripherals[index].1.lock().unwrap();
-            let _ = command_channel.send(peripheral::Command::SetLEDColor(new_color.clone()));
-            if let Some((light_info, _)) = &mut state.peripherals[index].0.lock().unwrap().light_info {
-                light_info.color.h = new_color.h;
-            }
+            let _ = light.set_color(new_color);
 
-            format!("Hue Set")
+            Ok(String::from("Hue Set"))
         }
     }
 }
 
-#[get("/<index>/saturation")]
-pub(crate) async fn get_saturation(index: usize, state: &State<PeripheralState>) -> String {
-    let light_info = get_latest_device_info(index, state).await;
+#[get("/<id>/saturation")]
+pub(crate) async fn get_saturation(id: &str, state: &State<PeripheralState>) -> Result<String, Status> {
+    let light = state.find(id).ok_or(Status::NotFound)?;
+    let light_info = legacy_light_info(get_latest_device_info(&light).await)?;
 
     let normalized_saturation = light_info.color.s;
 
     let saturation = (normalized_saturation * 100.0).round().clamp(0.0, 100.0) as u8;
 
-    format!("{}", saturation)
+    Ok(format!("{}", saturation))
 }
 
-#[put("/<index>/saturation", data = "<value>")]
-pub(crate) async fn set_saturation(index: usize, value: String, state: &State<PeripheralState>) -> String {
+#[put("/<id>/saturation", data = "<value>")]
+pub(crate) async fn set_saturation(id: &str, value: String, state: &State<PeripheralState>) -> Result<String, Status> {
     // TODO: Add Error type for faliure to parse
-    
+    let light = state.find(id).ok_or(Status::NotFound)?;
+
     match value.parse::<u8>() {
-        Err(error) => { format!("Parsing Error: {}", error) },
+        Err(error) => { Ok(format!("Parsing Error: {}", error)) },
         Ok(new_value) => {
-            let light_info = get_latest_device_info(index, state).await;
+            let light_info = legacy_light_info(get_latest_device_info(&light).await)?;
 
             let mut new_color = light_info.color.clone();
             new_color.s = (new_value as f64 / 100.0).clamp(0.0, 1.0);
 
-            let command_channel = &state.peripherals[index].1.lock().unwrap();
-            let _ = command_channel.send(peripheral::Command::SetLEDColor(new_color.clone()));
-            if let Some((light_info, _)) = &mut state.peripherals[index].0.lock().unwrap().light_info {
-                light_info.color.s = new_color.s;
-            }
+            let _ = light.set_color(new_color);
+
+            Ok(String::from("Saturation Set"))
+        }
+    }
+}
 
-            format!("Saturation Set")
+/// Legacy clients would rather show a stale value than an error, so they get the last known state
+/// of an unreachable light and only fail if there's none.
+fn legacy_light_info(result: Result<LightInfo, Unreachable>) -> Result<LightInfo, Status> {
+    match result {
+        Ok(light_info) => { Ok(light_info) }
+        Err(unreachable) => {
+            unreachable.last_known.map(|(light_info, _)| light_info).ok_or(Status::GatewayTimeout)
         }
     }
 }
 
-async fn get_latest_device_info(index: usize, state: &State<PeripheralState>) -> LightInfo {
-    _get_latest_device_info(index, state, false).await
+pub(crate) async fn get_latest_device_info(light: &Light) -> Result<LightInfo, Unreachable> {
+    get_device_info(light, false).await
 }
 
-async fn _get_latest_device_info(index: usize, state: &State<PeripheralState>, force_load: bool) -> LightInfo {
-    if force_load == false {
-        if let Some((light_info, timestamp)) = &state.peripherals[index].0.lock().unwrap().light_info {
-            let current_time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_millis();
-            if current_time - *timestamp < LIGHT_INFO_TTL {
-                return light_info.clone();
-            }
+/// Serves the cached light info while it's fresh, otherwise asks the light for it.
+pub(crate) async fn get_device_info(light: &Light, force_load: bool) -> Result<LightInfo, Unreachable> {
+    if !force_load {
+        if let Some(light_info) = light.run_state.lock().unwrap().fresh_light_info() {
+            return Ok(light_info);
         }
     }
 
-    //let mut request_in_flight = false;
-    loop {
-        if LIGHT_STATE_REQUEST_IN_FLIGHT.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
-        //if request_in_flight == false {
-            println!("Request out of date and no request in flight, sending");
-            let command_channel = &state.peripherals[index].1.lock().unwrap();
-            let _ = command_channel.send(peripheral::Command::GetDeviceInfo);
-            //request_in_flight = true;
+    request_device_info(light, None).await
+}
+
+/// Asks the light for its state and waits for the answer, asking again up to
+/// `device_info_retries` times. A request still in flight is shared unless it was sent before
+/// `not_before`, e.g. to make sure the answer reflects a command written at that time.
+pub(crate) async fn request_device_info(light: &Light, not_before: Option<Instant>) -> Result<LightInfo, Unreachable> {
+    let timing = light.timing();
+    let requested_at = current_time_ms();
+    let mut answered_rx = light.run_state.lock().unwrap().answered_tx.subscribe();
+    for attempt in 0..=timing.device_info_retries {
+        // Joins a request that's still in flight, a timed out one is considered lost.
+        let (request_id, is_new) = light.run_state.lock().unwrap().start_device_info_request(not_before);
+        if is_new {
+            println!("Requesting device info from {} (attempt {})", light.address(), attempt + 1);
+            if light.send(peripheral::Command::GetDeviceInfo).is_err() {
+                break;
+            }
         }
 
-        // TODO: Should add some timeout
-        sleep(Duration::from_millis(50)).await;
-        {
-            let current_time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_millis();
-            if let Some((light_info, timestamp)) = &state.peripherals[index].0.lock().unwrap().light_info {
-                if current_time - *timestamp < LIGHT_INFO_TTL {
-                    return light_info.clone();
+        let answered = timeout(timing.device_info_timeout(), answered_rx.wait_for(|answered_id| *answered_id >= request_id)).await;
+        match answered {
+            Ok(Ok(_)) => {
+                if let Some((light_info, _)) = light.cached_light_info() {
+                    return Ok(light_info);
                 }
             }
+            Ok(Err(_)) => { break }
+            Err(_) => {}
         }
     }
+
+    let unreachable = Unreachable {
+        address: light.address(),
+        waited: Duration::from_millis(current_time_ms().saturating_sub(requested_at) as u64),
+        last_known: light.cached_light_info().map(|(light_info, timestamp)| {
+            (light_info, Duration::from_millis(current_time_ms().saturating_sub(timestamp) as u64))
+        }),
+    };
+    eprintln!("{}", unreachable);
+
+    Err(unreachable)
 }
 
-pub(crate) async fn start(peripheral: &Peripheral) -> btleplug::Result<(RocketRunState, RocketCommandChannel)> {
-    let (mut home_light_peripheral, command_tx) = peripheral::HomeLightPeripheral::new(peripheral.clone());
+/// Starts supervising the light on `transport` and following it, changes are reported to
+/// `events`. The light is returned right away, commands wait until it's connected.
+pub(crate) fn start<T: Transport>(transport: T, timing: &TimingConfig, events: broadcast::Sender<LightEvent>) -> Light {
+    let address = transport.address();
+    let (mut home_light_peripheral, command_queue) = peripheral::HomeLightPeripheral::new(transport, timing.clone());
+
+    let (mut data_rx, supervisor) = home_light_peripheral.start_listening();
 
-    let mut data_rx = home_light_peripheral.start_listening().await?;
+    let mut run_state = RunState::new(address, timing.clone(), events);
+    run_state.supervisor = Some(supervisor);
+    // Ask for the device info right away, the light can't be looked up by name until we have it.
+    // It's tracked like any other request, so its answer isn't taken for a later one's.
+    run_state.start_device_info_request(None);
+    let _ = command_queue.push(peripheral::Command::GetDeviceInfo);
+    let run_state = Arc::new(Mutex::new(run_state));
+
+    let mut connection_rx = home_light_peripheral.connection_status();
+    let connection_run_state = run_state.clone();
+    rocket::tokio::spawn(async move {
+        loop {
+            let state = *connection_rx.borrow_and_update();
+            connection_run_state.lock().unwrap().set_connection_state(state);
+            if connection_rx.changed().await.is_err() {
+                break;
+            }
+        }
+    });
 
-    let run_state = Arc::new(Mutex::new(RunState::new()));
-    
     println!("Setting up decoder thread");
     let data_run_state = run_state.clone();
     rocket::tokio::spawn(async move {
-        loop {
-            while let Some(message) = data_rx.recv().await {
-                println!("Message Received: ({:?}) - {:?}", message.message_type, message.data);
-                match message.message_type {
-                    HomeLightMessageType::DeviceInfo => {
-                        if let Ok(info) = LightInfo::from_raw_data(&message.data) {
+        while let Some(message) = data_rx.recv().await {
+            println!("Message Received: ({:?}) - {:?}", message.message_type, message.data);
+            data_run_state.lock().unwrap().last_seen_ms = Some(current_time_ms());
+            match message.message_type {
+                HomeLightMessageType::DeviceInfo => {
+                    match LightInfo::from_raw_data(&message.data) {
+                        Ok(info) => {
                             println!("{:?}", info);
-                            let mut state = data_run_state.lock().unwrap();
-                            let current_time = SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .expect("Time went backwards")
-                                .as_millis();
-                            state.light_info = Some((info, current_time));
-                            LIGHT_STATE_REQUEST_IN_FLIGHT.store(false, Ordering::Release);
+                            data_run_state.lock().unwrap().answer_device_info_request(info);
                         }
+                        Err(error) => { eprintln!("Error parsing device info: {}", error) }
+                    }
+                }
+                HomeLightMessageType::DeviceColor => {
+                    // Pushed by the light whenever its color changes outside of our control,
+                    // e.g. from its physical buttons or the phone app.
+                    let color = HSVColor::from_raw_data([message.data[0], message.data[1], message.data[2]]);
+                    let mut state = data_run_state.lock().unwrap();
+                    if let Some((light_info, timestamp)) = &mut state.light_info {
+                        let current_time = current_time_ms();
+                        light_info.color = color;
+                        *timestamp = current_time;
+                        state.light_info_changed();
+                    } else {
+                        println!("No device info cached yet, ignoring color update");
                     }
-                    _ => { println!("Unhandled!") }
                 }
             }
         }
     });
 
-    Ok((run_state, Arc::new(Mutex::new(command_tx))))
+    Light { run_state, command_queue }
+}
+
+fn current_time_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
 }
 
 impl RunState {
-    fn new() -> Self {
+    fn new(address: String, timing: TimingConfig, events: broadcast::Sender<LightEvent>) -> Self {
         RunState {
-            light_info: None
+            address,
+            light_info: None,
+            timing,
+            device_info_requests: VecDeque::new(),
+            last_request_id: 0,
+            answered_tx: watch::channel(0).0,
+            connection_state: ConnectionState::Connecting,
+            last_seen_ms: None,
+            events,
+            supervisor: None,
+            transition: None,
+            last_transition_id: 0,
+        }
+    }
+
+    fn light_info_changed(&self) {
+        if let Some((light_info, _)) = &self.light_info {
+            // Nobody listening isn't an error.
+            let _ = self.events.send(LightEvent::State { address: self.address.clone(), state: light_info.clone() });
+        }
+    }
+
+    fn set_connection_state(&mut self, state: ConnectionState) {
+        if self.connection_state != state {
+            self.connection_state = state;
+            let _ = self.events.send(LightEvent::Connection { address: self.address.clone(), connected: state.is_connected(), state });
+        }
+    }
+
+    /// Returns the id of the request to wait on and whether it's new and has to be sent.
+    fn start_device_info_request(&mut self, not_before: Option<Instant>) -> (u64, bool) {
+        if let Some(request) = self.device_info_requests.back() {
+            let is_recent_enough = not_before.map(|not_before| request.sent_at >= not_before).unwrap_or(true);
+            if is_recent_enough && request.sent_at.elapsed() < self.timing.device_info_timeout() {
+                return (request.id, false);
+            }
+        }
+
+        // A light that never answers would otherwise leave every request behind.
+        self.drop_lost_device_info_requests();
+        self.last_request_id += 1;
+        self.device_info_requests.push_back(DeviceInfoRequest { id: self.last_request_id, sent_at: Instant::now() });
+
+        (self.last_request_id, true)
+    }
+
+    /// Caches device info reported by the light and wakes whoever was waiting on it.
+    fn answer_device_info_request(&mut self, light_info: LightInfo) {
+        self.light_info = Some((light_info, current_time_ms()));
+        self.light_info_changed();
+        self.drop_lost_device_info_requests();
+        if let Some(request) = self.device_info_requests.pop_front() {
+            self.answered_tx.send_replace(request.id);
+        }
+    }
+
+    /// Requests that timed out are considered lost, nobody waits on them anymore. The newest is
+    /// kept, a late answer is still taken for it.
+    fn drop_lost_device_info_requests(&mut self) {
+        let device_info_timeout = self.timing.device_info_timeout();
+        while self.device_info_requests.len() > 1 && self.device_info_requests[0].sent_at.elapsed() >= device_info_timeout {
+            self.device_info_requests.pop_front();
+        }
+    }
+
+    /// The cached light info, if it's younger than the TTL.
+    fn fresh_light_info(&self) -> Option<LightInfo> {
+        let (light_info, timestamp) = self.light_info.as_ref()?;
+        let current_time = current_time_ms();
+        match self.timing.light_info_ttl() {
+            Some(ttl) if current_time.saturating_sub(*timestamp) >= ttl.as_millis() => { None }
+            _ => { Some(light_info.clone()) }
+        }
+    }
+}
+
+/// Helpers for tests that run against simulated lights.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::queue::{self, CommandReceiver};
+    use crate::simulator::{SimulatedLight, SimulatedTransport};
+    use tokio::time::sleep;
+
+    /// How long a test waits for a light to get somewhere before it fails.
+    const WAIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Starts simulated light `index` and waits for its first report, so that report can't be
+    /// taken for the answer to a later change.
+    pub(crate) async fn started_light(index: usize, timing: &TimingConfig, events: broadcast::Sender<LightEvent>) -> Light {
+        let name = format!("Simulated Light {}", index);
+        let transport = SimulatedTransport::new(&format!("simulated-{}", index), SimulatedLight::new(&name));
+        let light = start(transport, timing, events);
+        wait_for_light_info(&light, |_| true).await;
+
+        light
+    }
+
+    /// A light whose commands are taken but never written, like one that's out of range. It last
+    /// reported `last_known` a second ago. Commands stay queued as long as the receiver is kept.
+    pub(crate) fn silent_light(address: &str, timing: &TimingConfig, last_known: LightInfo) -> (Light, CommandReceiver) {
+        let mut run_state = RunState::new(String::from(address), timing.clone(), broadcast::channel(LIGHT_EVENT_BUFFER_SIZE).0);
+        run_state.light_info = Some((last_known, current_time_ms() - 1_000));
+        let (command_queue, command_rx) = queue::command_queue(queue::COMMAND_QUEUE_SIZE);
+
+        (Light { run_state: Arc::new(Mutex::new(run_state)), command_queue }, command_rx)
+    }
+
+    /// Waits for the light's cached info to satisfy `predicate`, failing the test if it doesn't
+    /// within `WAIT_TIMEOUT`.
+    pub(crate) async fn wait_for_light_info(light: &Light, predicate: impl Fn(&LightInfo) -> bool) -> LightInfo {
+        let deadline = Instant::now() + WAIT_TIMEOUT;
+        loop {
+            if let Some((light_info, _)) = light.cached_light_info() {
+                if predicate(&light_info) {
+                    return light_info;
+                }
+            }
+            if Instant::now() >= deadline {
+                panic!("Light info never matched, last was {:?}", light.cached_light_info());
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::{silent_light, wait_for_light_info};
+    use crate::queue::{self, CommandOutcome};
+    use crate::simulator::{SimulatedLight, SimulatedTransport};
+
+    /// A silent light at `address` that last reported `name`.
+    fn named_light(address: &str, name: &str) -> Light {
+        let last_known = LightInfo { name: String::from(name), is_on: true, color: HSVColor { h: 0.0, s: 0.0, v: 1.0 }, animation: None, schedule: vec![] };
+
+        silent_light(address, &TimingConfig::default(), last_known).0
+    }
+
+    #[tokio::test]
+    async fn lights_are_found_by_address_name_or_index() {
+        let state = PeripheralState::new();
+        state.add_light(named_light("AA:BB:CC:DD:EE:01", "Kitchen"));
+        state.add_light(named_light("AA:BB:CC:DD:EE:02", "Porch"));
+
+        assert_eq!(state.find("AA:BB:CC:DD:EE:02").unwrap().address(), "AA:BB:CC:DD:EE:02");
+        assert_eq!(state.find("aa:bb:cc:dd:ee:02").unwrap().address(), "AA:BB:CC:DD:EE:02");
+        assert_eq!(state.find("Kitchen").unwrap().address(), "AA:BB:CC:DD:EE:01");
+        assert_eq!(state.find("porch").unwrap().address(), "AA:BB:CC:DD:EE:02");
+        assert_eq!(state.find("1").unwrap().address(), "AA:BB:CC:DD:EE:02");
+        assert!(state.find("2").is_none());
+        assert!(state.find("Attic").is_none());
+    }
+
+    #[tokio::test]
+    async fn addresses_win_over_names_and_names_over_indexes() {
+        let state = PeripheralState::new();
+        state.add_light(named_light("AA:BB:CC:DD:EE:01", "Kitchen"));
+        state.add_light(named_light("AA:BB:CC:DD:EE:02", "aa:bb:cc:dd:ee:01"));
+        state.add_light(named_light("AA:BB:CC:DD:EE:03", "0"));
+
+        assert_eq!(state.find("AA:BB:CC:DD:EE:01").unwrap().address(), "AA:BB:CC:DD:EE:01");
+        assert_eq!(state.find("0").unwrap().address(), "AA:BB:CC:DD:EE:03");
+    }
+
+    #[tokio::test]
+    async fn adding_a_known_address_replaces_the_light_in_place() {
+        let state = PeripheralState::new();
+        state.add_light(named_light("AA:BB:CC:DD:EE:01", "Kitchen"));
+        state.add_light(named_light("AA:BB:CC:DD:EE:02", "Porch"));
+        state.add_light(named_light("AA:BB:CC:DD:EE:01", "Hallway"));
+
+        let names: Vec<_> = state.lights().iter().map(|light| light.name().unwrap()).collect();
+        assert_eq!(names, vec!["Hallway", "Porch"]);
+        assert!(state.find("Kitchen").is_none());
+        assert_eq!(state.find("0").unwrap().name().unwrap(), "Hallway");
+    }
+
+    #[tokio::test]
+    async fn device_info_and_color_round_trip_through_a_simulated_light() {
+        let transport = SimulatedTransport::new("simulated-0", SimulatedLight::new("Simulated Light 0"));
+        let light = start(transport, &TimingConfig::default(), broadcast::channel(LIGHT_EVENT_BUFFER_SIZE).0);
+
+        light.command_queue.push(peripheral::Command::GetDeviceInfo).unwrap();
+        let light_info = wait_for_light_info(&light, |_| true).await;
+        assert_eq!(light_info.name, "Simulated Light 0");
+        assert!(light_info.is_on);
+
+        // The simulated light answers color changes with a DeviceColor message.
+        light.command_queue.push(peripheral::Command::SetLEDColor(HSVColor { h: 120.0, s: 1.0, v: 0.5 })).unwrap();
+        let light_info = wait_for_light_info(&light, |info| info.color.to_raw_data() == [85, 255, 128]).await;
+        assert!(light_info.animation.is_none());
+
+        light.command_queue.push(peripheral::Command::SetName(String::from("Porch"))).unwrap();
+        light.command_queue.push(peripheral::Command::SetBrightness(0.0)).unwrap();
+        light.command_queue.push(peripheral::Command::GetDeviceInfo).unwrap();
+        let light_info = wait_for_light_info(&light, |info| info.name == "Porch").await;
+        assert!(!light_info.is_on);
+        assert_eq!(light_info.color.to_raw_data(), [85, 255, 128]);
+    }
+
+    #[tokio::test]
+    async fn unanswered_requests_report_the_last_known_state() {
+        let timing = TimingConfig { device_info_timeout_ms: 100, device_info_retries: 1, ..TimingConfig::default() };
+        let mut run_state = RunState::new(String::from("silent"), timing, broadcast::channel(LIGHT_EVENT_BUFFER_SIZE).0);
+        let mut last_known = SimulatedLight::new("Silent Light").handle_command(peripheral::Command::GetDeviceInfo);
+        let last_known = LightInfo::from_raw_data(&last_known.remove(0).data).unwrap();
+        run_state.light_info = Some((last_known, current_time_ms() - 1_000));
+        // Commands are taken but never answered, like a light whose answers get lost.
+        let (command_queue, mut command_rx) = queue::command_queue(queue::COMMAND_QUEUE_SIZE);
+        let light = Light { run_state: Arc::new(Mutex::new(run_state)), command_queue };
+        let requests = tokio::spawn(async move {
+            let mut requests = 0;
+            while let Ok(Some(queued)) = timeout(Duration::from_millis(500), command_rx.pop()).await {
+                assert!(matches!(queued.command, peripheral::Command::GetDeviceInfo));
+                queued.complete(CommandOutcome::Sent);
+                requests += 1;
+            }
+            requests
+        });
+
+        let unreachable = get_device_info(&light, true).await.unwrap_err();
+        assert_eq!(unreachable.address, "silent");
+        assert!(unreachable.waited >= Duration::from_millis(200));
+        let (light_info, age) = unreachable.last_known.unwrap();
+        assert_eq!(light_info.name, "Silent Light");
+        assert!(age >= Duration::from_millis(1_000));
+
+        // One request per attempt, the retry doesn't wait on the lost one.
+        assert_eq!(requests.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_lost_answer_is_retried_and_shared_by_concurrent_requests() {
+        let timing = TimingConfig { device_info_timeout_ms: 100, device_info_retries: 1, ..TimingConfig::default() };
+        let run_state = Arc::new(Mutex::new(RunState::new(String::from("flaky"), timing, broadcast::channel(LIGHT_EVENT_BUFFER_SIZE).0)));
+        let (command_queue, mut command_rx) = queue::command_queue(queue::COMMAND_QUEUE_SIZE);
+        let light = Light { run_state: run_state.clone(), command_queue };
+
+        // Drops the answer to the first request and answers every one after it.
+        let responder = tokio::spawn(async move {
+            let mut simulated_light = SimulatedLight::new("Flaky Light");
+            let mut requests = 0;
+            while let Ok(Some(queued)) = timeout(Duration::from_millis(500), command_rx.pop()).await {
+                requests += 1;
+                let mut messages = simulated_light.handle_command(queued.command.clone());
+                queued.complete(CommandOutcome::Sent);
+                if requests > 1 {
+                    let light_info = LightInfo::from_raw_data(&messages.remove(0).data).unwrap();
+                    run_state.lock().unwrap().answer_device_info_request(light_info);
+                }
+            }
+            requests
+        });
+
+        let (first, second) = tokio::join!(get_device_info(&light, true), get_device_info(&light, true));
+        assert_eq!(first.unwrap().name, "Flaky Light");
+        assert_eq!(second.unwrap().name, "Flaky Light");
+
+        assert_eq!(responder.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn lost_requests_are_dropped_while_the_light_is_silent() {
+        let timing = TimingConfig { device_info_timeout_ms: 10, ..TimingConfig::default() };
+        let mut run_state = RunState::new(String::from("silent"), timing, broadcast::channel(LIGHT_EVENT_BUFFER_SIZE).0);
+        for _ in 0..5 {
+            run_state.start_device_info_request(None);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let (request_id, is_new) = run_state.start_device_info_request(None);
+        assert!(is_new);
+        // The newest lost request and the one just started.
+        assert_eq!(run_state.device_info_requests.len(), 2);
+        assert_eq!(run_state.device_info_requests.back().unwrap().id, request_id);
+    }
+
+    #[tokio::test]
+    async fn a_request_that_has_to_follow_a_write_gets_its_own_answer() {
+        let timing = TimingConfig { device_info_timeout_ms: 1_000, device_info_retries: 1, ..TimingConfig::default() };
+        let run_state = Arc::new(Mutex::new(RunState::new(String::from("busy"), timing, broadcast::channel(LIGHT_EVENT_BUFFER_SIZE).0)));
+        let (command_queue, mut command_rx) = queue::command_queue(queue::COMMAND_QUEUE_SIZE);
+        let light = Light { run_state: run_state.clone(), command_queue };
+
+        // Only starts answering once both requests are queued.
+        let responder = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut simulated_light = SimulatedLight::new("Busy Light");
+            let mut requests = 0;
+            while let Ok(Some(queued)) = timeout(Duration::from_millis(200), command_rx.pop()).await {
+                requests += 1;
+                let mut messages = simulated_light.handle_command(queued.command.clone());
+                queued.complete(CommandOutcome::Sent);
+                let light_info = LightInfo::from_raw_data(&messages.remove(0).data).unwrap();
+                run_state.lock().unwrap().answer_device_info_request(light_info);
+            }
+            requests
+        });
+
+        let started_at = Instant::now();
+        let (first, second) = tokio::join!(request_device_info(&light, None), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            request_device_info(&light, Some(Instant::now())).await
+        });
+        first.unwrap();
+        second.unwrap();
+        // Neither waited for a timeout, each request was written and answered once.
+        assert!(started_at.elapsed() < Duration::from_millis(1_000));
+        assert_eq!(responder.await.unwrap(), 2);
+    }
+
+    async fn next_event(events_rx: &mut broadcast::Receiver<LightEvent>) -> LightEvent {
+        timeout(Duration::from_secs(2), events_rx.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn changes_are_reported_as_events() {
+        let (events_tx, mut events_rx) = broadcast::channel(LIGHT_EVENT_BUFFER_SIZE);
+        let transport = SimulatedTransport::new("simulated-0", SimulatedLight::new("Simulated Light 0"));
+        let light = start(transport, &TimingConfig::default(), events_tx);
+
+        // The supervisor reports every step until the light is listened to.
+        loop {
+            match next_event(&mut events_rx).await {
+                LightEvent::Connection { state: ConnectionState::Subscribed, connected, .. } => {
+                    assert!(connected);
+                    break;
+                }
+                LightEvent::Connection { .. } => {}
+                event => { panic!("Unexpected event: {:?}", event) }
+            }
+        }
+        // The light is asked for its state when it's started.
+        match next_event(&mut events_rx).await {
+            LightEvent::State { address, state } => {
+                assert_eq!(address, "simulated-0");
+                assert!(state.is_on);
+            }
+            event => { panic!("Unexpected event: {:?}", event) }
         }
+
+        light.set_power(false).unwrap();
+        assert!(matches!(next_event(&mut events_rx).await, LightEvent::State { state: LightInfo { is_on: false, .. }, .. }));
+    }
+
+    async fn connection_states_until_subscribed(events_rx: &mut broadcast::Receiver<LightEvent>) -> Vec<ConnectionState> {
+        let mut states = Vec::new();
+        while states.last() != Some(&ConnectionState::Subscribed) {
+            if let LightEvent::Connection { state, .. } = next_event(events_rx).await {
+                states.push(state);
+            }
+        }
+
+        states
+    }
+
+    #[tokio::test]
+    async fn a_dropped_link_is_reconnected_and_listened_to_again() {
+        let (events_tx, mut events_rx) = broadcast::channel(LIGHT_EVENT_BUFFER_SIZE);
+        let timing = TimingConfig { health_check_interval_ms: 20, ..TimingConfig::default() };
+        let transport = SimulatedTransport::new("simulated-0", SimulatedLight::new("Simulated Light 0"));
+        let light = start(transport.clone(), &timing, events_tx);
+        connection_states_until_subscribed(&mut events_rx).await;
+
+        transport.disconnect().await.unwrap();
+        let states = connection_states_until_subscribed(&mut events_rx).await;
+        assert_eq!(states.first(), Some(&ConnectionState::Connecting));
+
+        // Answers are heard again on the new connection.
+        light.set_color(HSVColor { h: 240.0, s: 1.0, v: 1.0 }).unwrap();
+        light.send(peripheral::Command::SetName(String::from("Porch"))).unwrap();
+        light.send(peripheral::Command::GetDeviceInfo).unwrap();
+        wait_for_light_info(&light, |info| info.name == "Porch").await;
+        assert!(light.last_seen_ms().is_some());
+    }
+
+    #[tokio::test]
+    async fn stopping_writes_queued_commands_and_disconnects() {
+        let (events_tx, mut events_rx) = broadcast::channel(LIGHT_EVENT_BUFFER_SIZE);
+        let transport = SimulatedTransport::new("simulated-0", SimulatedLight::new("Simulated Light 0"));
+        let light = start(transport.clone(), &TimingConfig::default(), events_tx);
+        connection_states_until_subscribed(&mut events_rx).await;
+
+        let ticket = light.send(peripheral::Command::SetName(String::from("Porch"))).unwrap();
+        light.stop(Duration::from_secs(2)).await;
+
+        assert_eq!(ticket.outcome().await, CommandOutcome::Sent);
+        assert!(!transport.is_connected().await.unwrap());
+        assert_eq!(light.send(peripheral::Command::GetDeviceInfo).err(), Some(DropReason::Closed));
     }
 }
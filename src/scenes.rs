@@ -0,0 +1,118 @@
+use std::error::Error;
+use std::fmt;
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::light::HSVColor;
+use crate::queue::{CommandTicket, DropReason};
+use crate::runner::{self, Light, PeripheralState, Unreachable};
+use crate::store::{Collection, Named};
+
+/// The state of a set of lights saved under a name, e.g. "movie night".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Scene {
+    pub name: String,
+    pub lights: Vec<SceneLight>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SceneLight {
+    pub address: String,
+    pub is_on: bool,
+    pub color: HSVColor,
+}
+
+/// Scenes by name, saved to disk on every change.
+pub(crate) type SceneStore = Collection<Scene>;
+
+/// Why a light in a scene wasn't changed.
+#[derive(Debug)]
+pub(crate) enum ActivationError {
+    /// The light is gone, e.g. it was removed from the hub since the scene was saved.
+    UnknownLight(String),
+    Dropped(DropReason),
+}
+
+impl Named for Scene {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Takes the current power and color of every light, asking the ones whose cached state is stale.
+pub(crate) async fn snapshot(lights: &[Light]) -> Result<Vec<SceneLight>, Unreachable> {
+    let light_infos = join_all(lights.iter().map(runner::get_latest_device_info)).await;
+
+    let mut scene_lights = Vec::new();
+    for (light, light_info) in lights.iter().zip(light_infos) {
+        let light_info = light_info?;
+        scene_lights.push(SceneLight { address: light.address(), is_on: light_info.is_on, color: light_info.color });
+    }
+
+    Ok(scene_lights)
+}
+
+/// Queues the commands that bring `light` to its state in a scene. A light that's on gets its
+/// color before it's turned on, so it doesn't flash its old one.
+pub(crate) fn apply(light: &Light, scene_light: &SceneLight) -> Result<Vec<CommandTicket>, DropReason> {
+    if scene_light.is_on {
+        Ok(vec![light.set_color(scene_light.color.clone())?, light.set_power(true)?])
+    } else {
+        Ok(vec![light.set_power(false)?])
+    }
+}
+
+/// Looks up a light in a scene and queues the commands that bring it to its state there.
+pub(crate) fn activate_light(scene_light: &SceneLight, state: &PeripheralState) -> Result<(Light, Vec<CommandTicket>), ActivationError> {
+    let light = state.find(&scene_light.address).ok_or_else(|| ActivationError::UnknownLight(scene_light.address.clone()))?;
+    let tickets = apply(&light, scene_light).map_err(ActivationError::Dropped)?;
+
+    Ok((light, tickets))
+}
+
+impl fmt::Display for ActivationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActivationError::UnknownLight(address) => { write!(f, "No light matches {:?}", address) }
+            ActivationError::Dropped(reason) => { write!(f, "{}", reason) }
+        }
+    }
+}
+
+impl Error for ActivationError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    use crate::config::TimingConfig;
+    use crate::queue::CommandOutcome;
+    use crate::runner::testing::{started_light, wait_for_light_info};
+
+    #[tokio::test]
+    async fn scenes_are_saved_and_recalled() {
+        let path = std::env::temp_dir().join(format!("home-light-hub-scenes-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let light = started_light(0, &TimingConfig::default(), broadcast::channel(16).0).await;
+
+        light.set_color(HSVColor { h: 240.0, s: 1.0, v: 1.0 }).unwrap();
+        let lights = snapshot(std::slice::from_ref(&light)).await.unwrap();
+        assert_eq!(lights[0].color.to_raw_data(), [170, 255, 255]);
+        SceneStore::load(path).unwrap().insert(Scene { name: String::from("Movie night"), lights }).unwrap();
+
+        // A fresh store sees what the first one saved.
+        let scene = SceneStore::load(path).unwrap().get("Movie night").unwrap();
+        light.set_color(HSVColor { h: 0.0, s: 0.0, v: 1.0 }).unwrap();
+        light.set_power(false).unwrap();
+        for ticket in apply(&light, &scene.lights[0]).unwrap() {
+            assert_eq!(ticket.outcome().await, CommandOutcome::Sent);
+        }
+        wait_for_light_info(&light, |light_info| light_info.is_on && light_info.color.to_raw_data() == [170, 255, 255]).await;
+
+        SceneStore::load(path).unwrap().remove("Movie night").unwrap();
+        assert!(SceneStore::load(path).unwrap().list().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
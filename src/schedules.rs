@@ -0,0 +1,469 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, FixedOffset, Local, LocalResult, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+
+use crate::cron::{CronExpression, ParseError};
+use crate::groups::GroupStore;
+use crate::light::HSVColor;
+use crate::runner::{self, Light, PeripheralState};
+use crate::scenes::{self, SceneStore};
+use crate::solar::{Location, SolarEvent};
+use crate::store::{Collection, Named, StoreError};
+use crate::transition;
+
+/// Longest the scheduler sleeps before looking at the clock again, so it notices when the
+/// clock is set.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+/// Furthest a solar trigger can be moved from its event, either way.
+pub(crate) const MAX_SOLAR_OFFSET_MINUTES: i64 = 12 * 60;
+/// How far ahead to look for a solar event, near the poles the sun doesn't set for months.
+const SOLAR_SEARCH_DAYS: u32 = 370;
+
+/// Something the hub does on its own at given times, in the scheduler's time zone. Unlike the
+/// schedules lights keep themselves, these can do anything the API can and aren't limited in
+/// number.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Schedule {
+    pub name: String,
+    pub trigger: Trigger,
+    pub action: Action,
+    pub enabled: bool,
+    /// When the action last ran.
+    pub last_run: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum Trigger {
+    /// Once, e.g. at "2026-10-18T07:00:00". The schedule is disabled when it has run, it doesn't
+    /// run later if the hub wasn't running at the time.
+    Once { at: NaiveDateTime },
+    /// Every minute a cron expression like "30 7 * * 1-5" matches.
+    Cron { expression: String },
+    /// Every day at sunrise, sunset or twilight, moved by `offset_minutes`, e.g. -30 for half an
+    /// hour before. Skipped on days without the event. Needs the hub's location.
+    Solar {
+        event: SolarEvent,
+        #[serde(default)]
+        offset_minutes: i64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum Action {
+    Power { target: Target, on: bool },
+    /// Components left out keep their current value.
+    Color { target: Target, h: Option<f64>, s: Option<f64>, v: Option<f64> },
+    Transition { target: Target, h: Option<f64>, s: Option<f64>, v: Option<f64>, duration_ms: u64 },
+    Scene { scene: String },
+}
+
+/// The lights an action changes, looked up when it runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Target {
+    /// Anything a light can be looked up by.
+    Lights(Vec<String>),
+    Group(String),
+}
+
+/// What actions are run against.
+#[derive(Clone)]
+pub(crate) struct Hub {
+    pub state: PeripheralState,
+    pub scenes: SceneStore,
+    pub groups: GroupStore,
+}
+
+/// The time zone and place schedules run in.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Clock {
+    /// The system's time zone if unset.
+    pub time_zone: Option<Tz>,
+    /// Solar triggers never fire without one.
+    pub location: Option<Location>,
+}
+
+/// Schedules by name, saved to disk on every change. Clones share the same schedules.
+#[derive(Clone)]
+pub(crate) struct Scheduler {
+    store: Collection<Schedule>,
+    clock: Clock,
+    /// Wakes the scheduler task up when schedules change.
+    changed: Arc<Notify>,
+}
+
+impl Named for Schedule {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Clock {
+    /// `time` in the clock's time zone.
+    pub(crate) fn local(&self, time: &DateTime<Utc>) -> DateTime<FixedOffset> {
+        match &self.time_zone {
+            Some(time_zone) => { fixed(time.with_timezone(time_zone)) }
+            None => { fixed(time.with_timezone(&Local)) }
+        }
+    }
+
+    /// When `schedule` runs next after `after`, `None` if it's disabled or won't run again.
+    pub(crate) fn next_run(&self, schedule: &Schedule, after: &DateTime<Utc>) -> Option<DateTime<FixedOffset>> {
+        if !schedule.enabled {
+            return None;
+        }
+
+        let location = self.location.as_ref();
+        match &self.time_zone {
+            Some(time_zone) => { schedule.trigger.next_after(&after.with_timezone(time_zone), time_zone, location).map(fixed) }
+            None => { schedule.trigger.next_after(&after.with_timezone(&Local), &Local, location).map(fixed) }
+        }
+    }
+}
+
+fn fixed<Tz: TimeZone>(time: DateTime<Tz>) -> DateTime<FixedOffset> {
+    let offset = time.offset().fix();
+    time.with_timezone(&offset)
+}
+
+impl Trigger {
+    pub(crate) fn check(&self) -> Result<(), ParseError> {
+        match self {
+            Trigger::Once { .. } | Trigger::Solar { .. } => { Ok(()) }
+            Trigger::Cron { expression } => { expression.parse::<CronExpression>().map(|_| ()) }
+        }
+    }
+
+    /// The first time after `after` the trigger fires, in `time_zone`. Solar triggers never fire
+    /// without a `location`.
+    pub(crate) fn next_after<Tz: TimeZone>(&self, after: &DateTime<Tz>, time_zone: &Tz, location: Option<&Location>) -> Option<DateTime<Tz>> {
+        match self {
+            Trigger::Once { at } => {
+                // A time skipped by a clock change never comes.
+                match time_zone.from_local_datetime(at) {
+                    LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => { Some(time).filter(|time| time > after) }
+                    LocalResult::None => { None }
+                }
+            }
+            Trigger::Cron { expression } => {
+                expression.parse::<CronExpression>().ok()?.next_after(after, time_zone)
+            }
+            Trigger::Solar { event, offset_minutes } => {
+                let location = location?;
+                // The API doesn't take offsets past the limit, but a schedule saved by hand could
+                // have one, and too large a one would overflow below.
+                if !(-MAX_SOLAR_OFFSET_MINUTES..=MAX_SOLAR_OFFSET_MINUTES).contains(offset_minutes) {
+                    return None;
+                }
+                // The times are worked out anew for every day. The offset can move a time to the
+                // day before, so the search starts a day early.
+                let mut date = after.date().naive_local().pred();
+                for _ in 0..SOLAR_SEARCH_DAYS {
+                    if let Some(time) = event.time_on(date, location) {
+                        let time = time.with_timezone(time_zone) + chrono::Duration::minutes(*offset_minutes);
+                        if &time > after {
+                            return Some(time);
+                        }
+                    }
+                    date = date.succ();
+                }
+
+                None
+            }
+        }
+    }
+}
+
+impl Action {
+    /// Runs the action, problems with single lights are logged and don't stop the others.
+    pub(crate) async fn run(&self, hub: &Hub) {
+        if let Action::Scene { scene } = self {
+            let scene = match hub.scenes.get(scene) {
+                Some(scene) => { scene }
+                None => {
+                    eprintln!("No scene is named {:?}", scene);
+                    return;
+                }
+            };
+            for scene_light in scene.lights.iter() {
+                if let Err(err) = scenes::activate_light(scene_light, &hub.state) {
+                    eprintln!("Couldn't change {}: {}", scene_light.address, err);
+                }
+            }
+            return;
+        }
+
+        let lights = self.target_lights(hub);
+        join_all(lights.iter().map(|light| async move {
+            if let Err(err) = self.run_on(light).await {
+                eprintln!("Couldn't change {}: {}", light.address(), err);
+            }
+        })).await;
+    }
+
+    fn target_lights(&self, hub: &Hub) -> Vec<Light> {
+        let target = match self {
+            Action::Power { target, .. } | Action::Color { target, .. } | Action::Transition { target, .. } => { target }
+            Action::Scene { .. } => { return Vec::new() }
+        };
+        match target {
+            Target::Lights(ids) => {
+                ids.iter().filter_map(|id| {
+                    let light = hub.state.find(id);
+                    if light.is_none() {
+                        eprintln!("No light matches {:?}", id);
+                    }
+                    light
+                }).collect()
+            }
+            Target::Group(name) => {
+                match hub.groups.get(name) {
+                    Some(group) => { group.members(&hub.state) }
+                    None => {
+                        eprintln!("No group is named {:?}", name);
+                        Vec::new()
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_on(&self, light: &Light) -> Result<(), String> {
+        match self {
+            Action::Power { on, .. } => {
+                light.set_power(*on).map_err(|reason| reason.to_string())?;
+            }
+            Action::Color { h: Some(h), s: Some(s), v: Some(v), .. } => {
+                light.set_color(HSVColor { h: *h, s: *s, v: *v }).map_err(|reason| reason.to_string())?;
+            }
+            Action::Color { h, s, v, .. } => {
+                let current = runner::get_latest_device_info(light).await.map_err(|err| err.to_string())?;
+                light.set_color(current.color.with_components(*h, *s, *v)).map_err(|reason| reason.to_string())?;
+            }
+            Action::Transition { h, s, v, duration_ms, .. } => {
+                let current = runner::get_latest_device_info(light).await.map_err(|err| err.to_string())?;
+                let to = current.color.with_components(*h, *s, *v);
+                transition::start(light, &current, to, Duration::from_millis(*duration_ms)).map_err(|reason| reason.to_string())?;
+            }
+            Action::Scene { .. } => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl Scheduler {
+    /// Loads what was saved at `path`, nothing runs until the scheduler is started.
+    pub(crate) fn load(path: &str, clock: Clock) -> Result<Self, StoreError> {
+        Ok(Scheduler { store: Collection::load(path)?, clock, changed: Arc::new(Notify::new()) })
+    }
+
+    pub(crate) fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// When `schedule` runs next from now on, in the scheduler's time zone.
+    pub(crate) fn next_run(&self, schedule: &Schedule) -> Option<DateTime<FixedOffset>> {
+        self.clock.next_run(schedule, &Utc::now())
+    }
+
+    pub(crate) fn list(&self) -> Vec<Schedule> {
+        self.store.list()
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<Schedule> {
+        self.store.get(name)
+    }
+
+    /// Adds a schedule or replaces the one with the same name, returning the replaced one.
+    pub(crate) fn insert(&self, schedule: Schedule) -> Result<Option<Schedule>, StoreError> {
+        let replaced = self.store.insert(schedule)?;
+        self.changed.notify_one();
+
+        Ok(replaced)
+    }
+
+    pub(crate) fn remove(&self, name: &str) -> Result<Option<Schedule>, StoreError> {
+        let removed = self.store.remove(name)?;
+        self.changed.notify_one();
+
+        Ok(removed)
+    }
+
+    /// Runs due schedules in the background until the task is aborted.
+    pub(crate) fn start(&self, hub: Hub) -> JoinHandle<()> {
+        let scheduler = self.clone();
+        tokio::spawn(async move { scheduler.run(hub).await })
+    }
+
+    async fn run(&self, hub: Hub) {
+        // Only times after this are due, so nothing runs twice when the clock is set back.
+        let mut checked_until = Utc::now();
+        loop {
+            let next_run = self.list().iter().filter_map(|schedule| self.clock.next_run(schedule, &checked_until)).min();
+            let wait = next_run
+                .map(|next_run| (next_run.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+                .map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT));
+            tokio::select! {
+                _ = sleep(wait) => {}
+                _ = self.changed.notified() => {}
+            }
+
+            let now = Utc::now();
+            for schedule in self.list() {
+                if self.clock.next_run(&schedule, &checked_until).is_some_and(|next_run| next_run <= now) {
+                    self.run_schedule(schedule, now, &hub);
+                }
+            }
+            checked_until = checked_until.max(now);
+        }
+    }
+
+    fn run_schedule(&self, schedule: Schedule, now: DateTime<Utc>, hub: &Hub) {
+        // Only what running changes is saved, the schedule may have been edited or removed
+        // since it was listed.
+        let last_run = self.clock.local(&now);
+        let saved = self.store.modify(&schedule.name, |stored| {
+            stored.last_run = Some(last_run);
+            if let Trigger::Once { .. } = stored.trigger {
+                stored.enabled = false;
+            }
+        });
+        match saved {
+            Ok(Some(())) => {}
+            Ok(None) => {
+                println!("Schedule {:?} was removed, not running it", schedule.name);
+                return;
+            }
+            Err(err) => { eprintln!("Couldn't save schedule {:?}: {}", schedule.name, err) }
+        }
+        println!("Running schedule {:?}", schedule.name);
+
+        // A slow light shouldn't hold up other schedules.
+        let hub = hub.clone();
+        tokio::spawn(async move { schedule.action.run(&hub).await });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::TimingConfig;
+    use crate::runner::testing::{started_light, wait_for_light_info};
+
+    fn temporary_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("home-light-hub-{}-{}.json", name, std::process::id()));
+        String::from(path.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn a_one_shot_schedule_runs_once_and_is_kept_across_restarts() {
+        let state = PeripheralState::new();
+        let light = started_light(0, &TimingConfig::default(), state.events()).await;
+        state.add_light(light.clone());
+        let hub = Hub {
+            state,
+            scenes: SceneStore::load(&temporary_path("schedule-scenes")).unwrap(),
+            groups: GroupStore::load(&temporary_path("schedule-groups")).unwrap(),
+        };
+
+        let path = temporary_path("schedules");
+        let scheduler = Scheduler::load(&path, Clock::default()).unwrap();
+        let task = scheduler.start(hub);
+        let at = (Local::now() + chrono::Duration::milliseconds(500)).naive_local();
+        scheduler.insert(Schedule {
+            name: String::from("Lights out"),
+            trigger: Trigger::Once { at },
+            action: Action::Power { target: Target::Lights(vec![String::from("simulated-0")]), on: false },
+            enabled: true,
+            last_run: None,
+        }).unwrap();
+        assert!(light.cached_light_info().unwrap().0.is_on);
+
+        wait_for_light_info(&light, |light_info| !light_info.is_on).await;
+        assert!(Local::now().naive_local() >= at);
+        task.abort();
+
+        // A fresh scheduler sees that it ran.
+        let scheduler = Scheduler::load(&path, Clock::default()).unwrap();
+        let schedule = scheduler.get("Lights out").unwrap();
+        assert!(!schedule.enabled);
+        assert!(schedule.last_run.is_some());
+        assert_eq!(scheduler.next_run(&schedule), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn running_a_schedule_keeps_changes_made_meanwhile() {
+        let hub = Hub {
+            state: PeripheralState::new(),
+            scenes: SceneStore::load(&temporary_path("meanwhile-scenes")).unwrap(),
+            groups: GroupStore::load(&temporary_path("meanwhile-groups")).unwrap(),
+        };
+        let path = temporary_path("meanwhile");
+        let scheduler = Scheduler::load(&path, Clock::default()).unwrap();
+        let schedule = |on| Schedule {
+            name: String::from("Evening"),
+            trigger: Trigger::Cron { expression: String::from("0 18 * * *") },
+            action: Action::Power { target: Target::Lights(vec![String::from("simulated-0")]), on },
+            enabled: true,
+            last_run: None,
+        };
+
+        // Edited after it was listed, the edit stays.
+        scheduler.insert(schedule(false)).unwrap();
+        scheduler.run_schedule(schedule(true), Utc::now(), &hub);
+        let stored = scheduler.get("Evening").unwrap();
+        assert!(matches!(stored.action, Action::Power { on: false, .. }));
+        assert!(stored.last_run.is_some());
+
+        // Removed after it was listed, it stays gone.
+        scheduler.remove("Evening").unwrap();
+        scheduler.run_schedule(schedule(true), Utc::now(), &hub);
+        assert!(scheduler.get("Evening").is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn solar_triggers_follow_the_sun_every_day() {
+        let clock = Clock {
+            time_zone: Some(chrono_tz::Europe::Berlin),
+            location: Some(Location { latitude: 52.52, longitude: 13.405 }),
+        };
+        let schedule = Schedule {
+            name: String::from("Porch light"),
+            trigger: Trigger::Solar { event: SolarEvent::Sunset, offset_minutes: -30 },
+            action: Action::Scene { scene: String::from("Evening") },
+            enabled: true,
+            last_run: None,
+        };
+
+        // Sunset in Berlin is at about 15:54 local time in late December, 21:33 in late June.
+        let after = Utc.ymd(2026, 12, 21).and_hms(12, 0, 0);
+        let next_run = clock.next_run(&schedule, &after).unwrap();
+        let expected = FixedOffset::east(60 * 60).ymd(2026, 12, 21).and_hms(15, 24, 0);
+        assert!((next_run - expected).num_seconds().abs() <= 120, "{}", next_run);
+        let after_that = clock.next_run(&schedule, &next_run.with_timezone(&Utc)).unwrap();
+        assert_eq!(after_that.date().naive_local(), next_run.date().naive_local().succ());
+
+        let after = Utc.ymd(2026, 6, 21).and_hms(12, 0, 0);
+        let expected = FixedOffset::east(2 * 60 * 60).ymd(2026, 6, 21).and_hms(21, 3, 0);
+        assert!((clock.next_run(&schedule, &after).unwrap() - expected).num_seconds().abs() <= 120);
+
+        // An offset out of range, e.g. from a file edited by hand, never fires.
+        let out_of_range = Schedule { trigger: Trigger::Solar { event: SolarEvent::Sunset, offset_minutes: i64::MIN }, ..schedule.clone() };
+        assert_eq!(clock.next_run(&out_of_range, &after), None);
+
+        let without_location = Clock { location: None, ..clock };
+        assert_eq!(without_location.next_run(&schedule, &after), None);
+    }
+}
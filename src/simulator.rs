@@ -0,0 +1,168 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+
+use crate::decoder::{HomeLightMessage, HomeLightMessageType};
+use crate::light::{HSVColor, LightInfo, MAX_SCHEDULE_ENTRIES};
+use crate::peripheral::Command;
+use crate::transport::{self, Error, NotificationStream, Result, Transport};
+
+/// The Bluno forwards serial data over BLE in packets of at most this many bytes, messages are
+/// split the same way so the decoder sees realistic data.
+const NOTIFICATION_PACKET_SIZE: usize = 20;
+/// How many notification packets are buffered for a slow listener before old ones are dropped.
+const NOTIFICATION_BUFFER_SIZE: usize = 64;
+
+/// A software HomeLight that understands the same commands as the firmware and answers with the
+/// same messages.
+pub(crate) struct SimulatedLight {
+    info: LightInfo,
+}
+
+impl SimulatedLight {
+    pub fn new(name: &str) -> Self {
+        SimulatedLight {
+            info: LightInfo {
+                name: String::from(name),
+                is_on: true,
+                color: HSVColor { h: 0.0, s: 0.0, v: 1.0 },
+                animation: None,
+                schedule: Vec::new(),
+            }
+        }
+    }
+
+    /// Applies a single command and returns the messages the firmware would send in response.
+    pub fn handle_command(&mut self, command: Command) -> Vec<HomeLightMessage> {
+        match command {
+            Command::SetName(name) => {
+                self.info.name = name;
+                vec![]
+            }
+            Command::Echo(_) => {
+                vec![]
+            }
+            Command::SetLEDColor(color) => {
+                self.info.color = color;
+                self.info.animation = None;
+                vec![self.device_color_message()]
+            }
+            Command::SetBrightness(brightness) => {
+                self.info.is_on = brightness > 0.0;
+                vec![]
+            }
+            Command::GetDeviceInfo => {
+                match self.info.to_raw_data() {
+                    Ok(data) => {
+                        vec![HomeLightMessage { message_type: HomeLightMessageType::DeviceInfo, data }]
+                    }
+                    Err(error) => {
+                        eprintln!("Simulated light can't report its info: {}", error);
+                        vec![]
+                    }
+                }
+            }
+            Command::SetAnimation(animation) => {
+                if let Some(keyframe) = animation.keyframes.first() {
+                    self.info.color = keyframe.color.clone();
+                }
+                self.info.animation = Some(animation);
+                vec![]
+            }
+            Command::GetColorInfo => {
+                vec![self.device_color_message()]
+            }
+            Command::SetSchedule(entry) => {
+                // Like the firmware, a full schedule only takes changes to slots it already has.
+                let is_new_slot = !self.info.schedule.iter().any(|existing| existing.slot == entry.slot);
+                if is_new_slot && self.info.schedule.len() >= MAX_SCHEDULE_ENTRIES {
+                    return vec![];
+                }
+                self.info.schedule.retain(|existing| existing.slot != entry.slot);
+                self.info.schedule.push(entry);
+                self.info.schedule.sort_by_key(|entry| entry.slot);
+                vec![]
+            }
+            Command::ClearSchedule(Some(slot)) => {
+                self.info.schedule.retain(|entry| entry.slot != slot);
+                vec![]
+            }
+            Command::ClearSchedule(None) => {
+                self.info.schedule.clear();
+                vec![]
+            }
+        }
+    }
+
+    fn device_color_message(&self) -> HomeLightMessage {
+        HomeLightMessage { message_type: HomeLightMessageType::DeviceColor, data: self.info.color.to_raw_data().to_vec() }
+    }
+}
+
+/// Connects the hub to a `SimulatedLight` running in the same process.
+#[derive(Clone)]
+pub(crate) struct SimulatedTransport {
+    address: String,
+    light: Arc<Mutex<SimulatedLight>>,
+    is_connected: Arc<AtomicBool>,
+    notification_tx: broadcast::Sender<Vec<u8>>,
+}
+
+impl SimulatedTransport {
+    pub fn new(address: &str, light: SimulatedLight) -> Self {
+        let (notification_tx, _) = broadcast::channel(NOTIFICATION_BUFFER_SIZE);
+
+        SimulatedTransport {
+            address: String::from(address),
+            light: Arc::new(Mutex::new(light)),
+            is_connected: Arc::new(AtomicBool::new(false)),
+            notification_tx,
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for SimulatedTransport {
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    async fn connect(&self) -> Result<()> {
+        self.is_connected.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.is_connected.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.is_connected.load(Ordering::Acquire))
+    }
+
+    async fn write_frame(&self, frame: &[u8]) -> Result<()> {
+        if !self.is_connected.load(Ordering::Acquire) {
+            return Err(Error::NotConnected);
+        }
+
+        let command = Command::from_raw_data(frame)
+            .ok_or_else(|| Error::NotSupported(format!("Simulated light can't handle frame: {:?}", frame)))?;
+        let messages = self.light.lock().unwrap().handle_command(command);
+        for message in messages.iter() {
+            let raw_data = message.get_raw_data().map_err(|error| Error::NotSupported(error.to_string()))?;
+            for packet in raw_data.chunks(NOTIFICATION_PACKET_SIZE) {
+                // Nobody listening isn't an error, the real light doesn't know either.
+                let _ = self.notification_tx.send(packet.to_vec());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        Ok(transport::broadcast_notifications(self.notification_tx.subscribe()))
+    }
+}
@@ -0,0 +1,104 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Julian date of 2000-01-01 12:00 UTC, the epoch the formulas below count from.
+const J2000: f64 = 2_451_545.0;
+/// Julian date of the Unix epoch.
+const UNIX_EPOCH_JULIAN_DATE: f64 = 2_440_587.5;
+
+/// Where the hub is, for working out when the sun rises and sets there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Location {
+    /// Degrees, north is positive.
+    pub latitude: f64,
+    /// Degrees, east is positive.
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SolarEvent {
+    /// The sun is 6° below the horizon in the morning, it's light enough to see outside.
+    CivilDawn,
+    Sunrise,
+    Sunset,
+    /// The sun is 6° below the horizon in the evening, it's getting too dark to see outside.
+    CivilDusk,
+}
+
+impl SolarEvent {
+    /// When the event happens on `date` at `location`, `None` on days the sun doesn't get that
+    /// high or low, e.g. in polar summer. Accurate to about a minute, good enough for lights.
+    ///
+    /// Follows the sunrise equation as given on Wikipedia, which is a simplification of the
+    /// NOAA solar calculator.
+    pub(crate) fn time_on(self, date: NaiveDate, location: &Location) -> Option<DateTime<Utc>> {
+        let days = (date - NaiveDate::from_ymd(2000, 1, 1)).num_days() as f64;
+        let mean_solar_time = days - location.longitude / 360.0;
+        let mean_anomaly = (357.5291 + 0.985_600_28 * mean_solar_time).rem_euclid(360.0).to_radians();
+        let center = 1.9148 * mean_anomaly.sin() + 0.0200 * (2.0 * mean_anomaly).sin() + 0.0003 * (3.0 * mean_anomaly).sin();
+        let ecliptic_longitude = (mean_anomaly.to_degrees() + center + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+        let transit = J2000 + mean_solar_time + 0.0053 * mean_anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+        let declination = (ecliptic_longitude.sin() * 23.4397_f64.to_radians().sin()).asin();
+
+        let latitude = location.latitude.to_radians();
+        let cos_hour_angle = (self.elevation().to_radians().sin() - latitude.sin() * declination.sin())
+            / (latitude.cos() * declination.cos());
+        if !(-1.0..=1.0).contains(&cos_hour_angle) {
+            return None;
+        }
+        let hour_angle = cos_hour_angle.acos().to_degrees() / 360.0;
+        let julian_date = if self.is_morning() { transit - hour_angle } else { transit + hour_angle };
+
+        let unix_seconds = ((julian_date - UNIX_EPOCH_JULIAN_DATE) * 86_400.0).round() as i64;
+        Some(Utc.timestamp(unix_seconds, 0))
+    }
+
+    /// Elevation of the sun's center in degrees. Sunrise and sunset are a bit below zero, for
+    /// the refraction of the atmosphere and the size of the sun.
+    fn elevation(self) -> f64 {
+        match self {
+            SolarEvent::Sunrise | SolarEvent::Sunset => { -0.833 }
+            SolarEvent::CivilDawn | SolarEvent::CivilDusk => { -6.0 }
+        }
+    }
+
+    fn is_morning(self) -> bool {
+        matches!(self, SolarEvent::CivilDawn | SolarEvent::Sunrise)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BERLIN: Location = Location { latitude: 52.52, longitude: 13.405 };
+
+    fn assert_close(time: Option<DateTime<Utc>>, expected: &str) {
+        let expected = Utc.datetime_from_str(expected, "%Y-%m-%d %H:%M").unwrap();
+        let difference = (time.unwrap() - expected).num_seconds().abs();
+        assert!(difference <= 120, "{:?} is {}s off {:?}", time, difference, expected);
+    }
+
+    #[test]
+    fn sun_rises_and_sets_when_expected() {
+        // Published times for Berlin, rounded to the minute, in UTC.
+        let date = NaiveDate::from_ymd(2026, 6, 21);
+        assert_close(SolarEvent::Sunrise.time_on(date, &BERLIN), "2026-06-21 02:43");
+        assert_close(SolarEvent::Sunset.time_on(date, &BERLIN), "2026-06-21 19:33");
+
+        let date = NaiveDate::from_ymd(2026, 12, 21);
+        assert_close(SolarEvent::CivilDawn.time_on(date, &BERLIN), "2026-12-21 06:33");
+        assert_close(SolarEvent::Sunrise.time_on(date, &BERLIN), "2026-12-21 07:15");
+        assert_close(SolarEvent::Sunset.time_on(date, &BERLIN), "2026-12-21 14:54");
+        assert_close(SolarEvent::CivilDusk.time_on(date, &BERLIN), "2026-12-21 15:36");
+    }
+
+    #[test]
+    fn polar_days_have_no_sunset() {
+        let tromso = Location { latitude: 69.65, longitude: 18.96 };
+        assert_eq!(SolarEvent::Sunset.time_on(NaiveDate::from_ymd(2026, 6, 21), &tromso), None);
+        assert_eq!(SolarEvent::Sunrise.time_on(NaiveDate::from_ymd(2026, 12, 21), &tromso), None);
+        assert!(SolarEvent::CivilDawn.time_on(NaiveDate::from_ymd(2026, 12, 21), &tromso).is_some());
+    }
+}
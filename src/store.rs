@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use rocket::serde::json;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Something kept in a `Collection`, names are unique within one.
+pub(crate) trait Named {
+    fn name(&self) -> &str;
+}
+
+/// Things kept by name, e.g. scenes, saved to disk on every change. Clones share the same items.
+pub(crate) struct Collection<T> {
+    path: PathBuf,
+    items: Arc<RwLock<BTreeMap<String, T>>>,
+}
+
+impl<T: Named + Clone + Serialize + DeserializeOwned> Collection<T> {
+    /// Loads what was saved at `path`, nothing if nothing was saved there yet.
+    pub(crate) fn load(path: &str) -> Result<Self, StoreError> {
+        let path = PathBuf::from(path);
+        let items: Vec<T> = load(&path)?;
+        let items = items.into_iter().map(|item| (String::from(item.name()), item)).collect();
+
+        Ok(Collection { path, items: Arc::new(RwLock::new(items)) })
+    }
+
+    /// Everything, ordered by name.
+    pub(crate) fn list(&self) -> Vec<T> {
+        self.items.read().unwrap().values().cloned().collect()
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<T> {
+        self.items.read().unwrap().get(name).cloned()
+    }
+
+    /// Adds an item or replaces the one with the same name, returning the replaced one.
+    pub(crate) fn insert(&self, item: T) -> Result<Option<T>, StoreError> {
+        self.update(|items| items.insert(String::from(item.name()), item))
+    }
+
+    /// Adds an item unless one with the same name exists, returning whether it was added.
+    pub(crate) fn insert_new(&self, item: T) -> Result<bool, StoreError> {
+        self.update(|items| {
+            if items.contains_key(item.name()) {
+                return false;
+            }
+            items.insert(String::from(item.name()), item);
+            true
+        })
+    }
+
+    /// Applies `change` to the item named `name` and saves it, `None` if there's no such item.
+    pub(crate) fn modify<R>(&self, name: &str, change: impl FnOnce(&mut T) -> R) -> Result<Option<R>, StoreError> {
+        self.update(|items| items.get_mut(name).map(change))
+    }
+
+    pub(crate) fn remove(&self, name: &str) -> Result<Option<T>, StoreError> {
+        self.update(|items| items.remove(name))
+    }
+
+    /// Applies `change` and saves the result, the items are left as they were if saving fails.
+    fn update<R>(&self, change: impl FnOnce(&mut BTreeMap<String, T>) -> R) -> Result<R, StoreError> {
+        let mut items = self.items.write().unwrap();
+        let mut changed = items.clone();
+        let result = change(&mut changed);
+        save(&self.path, &changed.values().collect::<Vec<_>>())?;
+        *items = changed;
+
+        Ok(result)
+    }
+}
+
+impl<T> Clone for Collection<T> {
+    fn clone(&self) -> Self {
+        Collection { path: self.path.clone(), items: self.items.clone() }
+    }
+}
+
+/// Reads a value saved with `save`, a file that doesn't exist yet reads as the default.
+pub(crate) fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T, StoreError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => { contents }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => { return Ok(T::default()) }
+        Err(err) => { return Err(StoreError::Io(path.to_path_buf(), err)) }
+    };
+
+    json::from_str(&contents).map_err(|err| StoreError::Invalid(path.to_path_buf(), err.to_string()))
+}
+
+/// Saves `value` as JSON. It's written next to `path` first and then moved over it, so a crash
+/// can't leave a half written file behind.
+pub(crate) fn save<T: Serialize>(path: &Path, value: &T) -> Result<(), StoreError> {
+    // Only plain data is saved.
+    let contents = json::to_pretty_string(value).unwrap();
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    let temporary_path = PathBuf::from(temporary_path);
+
+    fs::write(&temporary_path, contents)
+        .and_then(|_| fs::rename(&temporary_path, path))
+        .map_err(|err| StoreError::Io(path.to_path_buf(), err))
+}
+
+#[derive(Debug)]
+pub(crate) enum StoreError {
+    Io(PathBuf, io::Error),
+    /// The file exists but doesn't hold what we saved.
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Io(path, error) => { write!(f, "Couldn't access {:?}: {}", path, error) }
+            StoreError::Invalid(path, message) => { write!(f, "Couldn't read {:?}: {}", path, message) }
+        }
+    }
+}
+
+impl Error for StoreError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Item {
+        name: String,
+        value: u32,
+    }
+
+    impl Named for Item {
+        fn name(&self) -> &str {
+            &self.name
+        }
+    }
+
+    #[test]
+    fn new_items_dont_replace_existing_ones() {
+        let path = std::env::temp_dir().join(format!("home-light-hub-store-{}.json", std::process::id()));
+        let collection = Collection::load(path.to_str().unwrap()).unwrap();
+
+        assert!(collection.insert_new(Item { name: String::from("a"), value: 1 }).unwrap());
+        assert!(!collection.insert_new(Item { name: String::from("a"), value: 2 }).unwrap());
+        assert_eq!(collection.get("a").unwrap().value, 1);
+
+        let reloaded: Collection<Item> = Collection::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(reloaded.list(), vec![Item { name: String::from("a"), value: 1 }]);
+        fs::remove_file(path).unwrap();
+    }
+}
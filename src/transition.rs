@@ -0,0 +1,169 @@
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::light::{HSVColor, LightInfo};
+use crate::queue::DropReason;
+use crate::runner::Light;
+
+/// Longest transition that can be started, long enough for a slow wake-up fade.
+pub(crate) const MAX_TRANSITION_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A fade between two colors, hue takes the shorter way around the color wheel.
+#[derive(Debug, Clone)]
+pub(crate) struct Fade {
+    from: HSVColor,
+    to: HSVColor,
+}
+
+impl Fade {
+    pub(crate) fn new(from: HSVColor, to: HSVColor) -> Self {
+        // A gray has no hue to speak of, fading from or to one shouldn't sweep through others.
+        let mut from = from;
+        let mut to = to;
+        if from.s == 0.0 {
+            from.h = to.h;
+        } else if to.s == 0.0 {
+            to.h = from.h;
+        }
+
+        Fade { from, to }
+    }
+
+    /// The color `progress` of the way through, from 0 to 1.
+    pub(crate) fn color_at(&self, progress: f64) -> HSVColor {
+        let progress = progress.clamp(0.0, 1.0);
+        let mut hue_delta = (self.to.h - self.from.h) % 360.0;
+        if hue_delta > 180.0 {
+            hue_delta -= 360.0;
+        } else if hue_delta < -180.0 {
+            hue_delta += 360.0;
+        }
+
+        HSVColor {
+            h: (self.from.h + hue_delta * progress).rem_euclid(360.0),
+            s: self.from.s + (self.to.s - self.from.s) * progress,
+            v: self.from.v + (self.to.v - self.from.v) * progress,
+        }
+    }
+}
+
+/// Fades `light` from its current state to `to` over `duration` in the background. A light
+/// that's off fades in from dark. Any newer color or brightness change cancels the fade, it then
+/// stops where it is.
+///
+/// Steps are queued once per command interval, the rate the link is known to keep up with, and
+/// only when the color the light can show changes, so a slow fade doesn't write the same bytes
+/// over and over.
+pub(crate) fn start(light: &Light, current: &LightInfo, to: HSVColor, duration: Duration) -> Result<(), DropReason> {
+    let mut from = current.color.clone();
+    if !current.is_on {
+        from.v = 0.0;
+        light.set_color(from.clone())?;
+        light.set_power(true)?;
+    }
+
+    let id = light.begin_transition();
+    let fade = Fade::new(from, to);
+    let light = light.clone();
+    println!("Starting a {:?} transition on {}: {:?}", duration, light.address(), fade);
+    tokio::spawn(async move {
+        let step_interval = light.timing().command_interval();
+        let started_at = Instant::now();
+        let mut last_sent = None;
+        loop {
+            let progress = if duration.is_zero() { 1.0 } else { started_at.elapsed().as_secs_f64() / duration.as_secs_f64() };
+            let color = fade.color_at(progress);
+            if last_sent != Some(color.to_raw_data()) {
+                last_sent = Some(color.to_raw_data());
+                match light.send_transition_step(id, color) {
+                    Ok(Some(_)) => {}
+                    Ok(None) => {
+                        println!("Transition on {} was cancelled", light.address());
+                        return;
+                    }
+                    Err(reason) => {
+                        eprintln!("Stopping transition on {}: {}", light.address(), reason);
+                        light.end_transition(id);
+                        return;
+                    }
+                }
+            }
+            if progress >= 1.0 {
+                break;
+            }
+            sleep(step_interval).await;
+        }
+
+        light.end_transition(id);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    use crate::config::TimingConfig;
+    use crate::runner::testing::{self, wait_for_light_info};
+
+    fn color(h: f64, s: f64, v: f64) -> HSVColor {
+        HSVColor { h, s, v }
+    }
+
+    #[test]
+    fn hue_takes_the_short_way_around() {
+        let fade = Fade::new(color(350.0, 1.0, 1.0), color(30.0, 1.0, 1.0));
+        assert!((fade.color_at(0.25).h - 0.0).abs() < 1e-9);
+        assert!((fade.color_at(0.5).h - 10.0).abs() < 1e-9);
+
+        let fade = Fade::new(color(30.0, 1.0, 1.0), color(350.0, 1.0, 1.0));
+        assert!((fade.color_at(0.5).h - 10.0).abs() < 1e-9);
+        assert!((fade.color_at(1.0).h - 350.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fading_from_white_keeps_the_target_hue() {
+        let fade = Fade::new(color(0.0, 0.0, 1.0), color(240.0, 1.0, 0.5));
+        let halfway = fade.color_at(0.5);
+        assert_eq!(halfway.h, 240.0);
+        assert!((halfway.s - 0.5).abs() < 1e-9);
+        assert!((halfway.v - 0.75).abs() < 1e-9);
+    }
+
+    async fn started_light() -> Light {
+        testing::started_light(0, &TimingConfig { command_interval_ms: 10, ..TimingConfig::default() }, broadcast::channel(16).0).await
+    }
+
+    #[tokio::test]
+    async fn a_light_that_is_off_fades_in_to_the_target() {
+        let light = started_light().await;
+        light.set_power(false).unwrap();
+        let (current, _) = light.cached_light_info().unwrap();
+
+        start(&light, &current, color(120.0, 1.0, 1.0), Duration::from_millis(200)).unwrap();
+        assert!(light.is_transitioning());
+        let (light_info, _) = light.cached_light_info().unwrap();
+        assert!(light_info.is_on);
+        assert!(light_info.color.v < 0.5);
+
+        wait_for_light_info(&light, |light_info| {
+            !light.is_transitioning() && light_info.color.to_raw_data() == color(120.0, 1.0, 1.0).to_raw_data()
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn a_newer_color_cancels_the_transition() {
+        let light = started_light().await;
+        let (current, _) = light.cached_light_info().unwrap();
+
+        start(&light, &current, color(120.0, 1.0, 1.0), Duration::from_secs(20 * 60)).unwrap();
+        sleep(Duration::from_millis(50)).await;
+        light.set_color(color(240.0, 1.0, 1.0)).unwrap();
+        assert!(!light.is_transitioning());
+
+        // No step of the cancelled transition lands after the new color.
+        sleep(Duration::from_millis(100)).await;
+        wait_for_light_info(&light, |light_info| light_info.color.to_raw_data() == color(240.0, 1.0, 1.0).to_raw_data()).await;
+    }
+}
@@ -0,0 +1,82 @@
+use std::error;
+use std::fmt;
+use std::pin::Pin;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::{stream, Stream};
+use tokio::sync::broadcast;
+
+pub(crate) mod ble;
+pub(crate) mod serial;
+
+pub(crate) type NotificationStream = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>;
+
+/// A link to a single HomeLight that can carry framed commands to it and raw notification data
+/// back from it.
+///
+/// Implementations are cheap handles to the underlying connection, clones talk to the same light.
+#[async_trait]
+pub(crate) trait Transport: Clone + Send + Sync + 'static {
+    /// A human readable identifier for the light on the other end, e.g. its BLE address.
+    fn address(&self) -> String;
+
+    /// Establishes the link and prepares it to exchange frames.
+    async fn connect(&self) -> Result<()>;
+
+    /// Stops notifications from the light, before disconnecting for good. Transports whose
+    /// notifications end with the connection have nothing to do.
+    async fn unsubscribe(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()>;
+
+    async fn is_connected(&self) -> Result<bool>;
+
+    /// Writes a single framed command, as produced by `Command::get_raw_data`.
+    async fn write_frame(&self, frame: &[u8]) -> Result<()>;
+
+    /// Raw bytes received from the light, they are not guaranteed to line up with message
+    /// boundaries and should be fed through a `HomeLightDecoder`.
+    async fn notifications(&self) -> Result<NotificationStream>;
+}
+
+/// Turns a receiver of raw packets into a notification stream, for transports that fan incoming
+/// data out to listeners themselves.
+pub(crate) fn broadcast_notifications(notification_rx: broadcast::Receiver<Vec<u8>>) -> NotificationStream {
+    Box::pin(stream::unfold(notification_rx, |mut notification_rx| async move {
+        loop {
+            match notification_rx.recv().await {
+                Ok(packet) => { return Some((packet, notification_rx)) }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    eprintln!("Notification listener fell behind, dropped {} packets", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => { return None }
+            }
+        }
+    }))
+}
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    NotConnected,
+    NotSupported(String),
+    TimedOut(Duration),
+    Other(Box<dyn error::Error + Send + Sync>),
+}
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotConnected => { write!(f, "Not connected") }
+            Error::NotSupported(reason) => { write!(f, "The operation is not supported: {}", reason) }
+            Error::TimedOut(duration) => { write!(f, "Timed out after {:?}", duration) }
+            Error::Other(error) => { write!(f, "{}", error) }
+        }
+    }
+}
+
+impl error::Error for Error {}
@@ -0,0 +1,171 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use btleplug::api::{Characteristic, CharPropFlags, Peripheral as _, WriteType};
+use btleplug::platform::Peripheral;
+use futures::StreamExt;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use crate::config::BluetoothConfig;
+use super::{Error, NotificationStream, Result, Transport};
+
+/// Talks to a light over Bluetooth LE through the serial characteristic of the Bluno board.
+#[derive(Clone)]
+pub(crate) struct BleTransport {
+    peripheral: Peripheral,
+    /// UUID of the characteristic for which we should subscribe to notifications.
+    notify_characteristic_uuid: Uuid,
+    characteristic: Arc<Mutex<Option<Characteristic>>>,
+    connect_timeout: Duration,
+    connect_fallback_command: Option<Vec<String>>,
+}
+
+impl BleTransport {
+    pub fn new(peripheral: Peripheral, config: &BluetoothConfig) -> Self {
+        BleTransport {
+            peripheral,
+            notify_characteristic_uuid: config.notify_characteristic_uuid(),
+            characteristic: Arc::new(Mutex::new(None)),
+            connect_timeout: config.connect_timeout(),
+            connect_fallback_command: config.connect_fallback_command.clone(),
+        }
+    }
+
+    /// Connects through the Bluetooth stack, which can hang on some adapters so it's given up on
+    /// after the connect timeout.
+    async fn connect_peripheral(&self) -> Result<()> {
+        // btleplug's errors can't be held across an await, so convert before matching.
+        let connected = timeout(self.connect_timeout, self.peripheral.connect()).await
+            .map(|result| result.map_err(Error::from));
+        match connected {
+            Ok(result) => { result }
+            Err(_) => {
+                // Don't leave the stack trying in the background.
+                let _ = self.peripheral.disconnect().await;
+                Err(Error::TimedOut(self.connect_timeout))
+            }
+        }
+    }
+}
+
+/// Runs the configured fallback command with `{address}` in its arguments replaced by `address`.
+async fn run_connect_fallback(command: &[String], address: &str) -> Result<()> {
+    let args: Vec<String> = command.iter().map(|arg| arg.replace("{address}", address)).collect();
+    let status = async_process::Command::new(&args[0]).args(&args[1..]).status().await
+        .map_err(|err| Error::Other(format!("Couldn't run connect fallback {:?}: {}", args, err).into()))?;
+    if !status.success() {
+        return Err(Error::Other(format!("Connect fallback {:?} failed: {}", args, status).into()));
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Transport for BleTransport {
+    fn address(&self) -> String {
+        self.peripheral.address().to_string()
+    }
+
+    async fn connect(&self) -> Result<()> {
+        if !self.peripheral.is_connected().await? {
+            if let Err(err) = self.connect_peripheral().await {
+                let command = match &self.connect_fallback_command {
+                    Some(command) => { command }
+                    None => { return Err(err) }
+                };
+                eprintln!("Error connecting to {}, running connect fallback: {}", self.address(), err);
+                run_connect_fallback(command, &self.address()).await?;
+                self.connect_peripheral().await?;
+            }
+        }
+
+        let chars = self.peripheral.discover_characteristics().await?;
+        for characteristic in chars.into_iter() {
+            // Subscribe to notifications from the characteristic with the selected
+            // UUID.
+            if characteristic.uuid == self.notify_characteristic_uuid
+                && characteristic.properties.contains(CharPropFlags::NOTIFY)
+            {
+                println!("Subscribing to characteristic {:?}", characteristic.uuid);
+                self.peripheral.subscribe(&characteristic).await?;
+                println!("Subscribed");
+
+                *self.characteristic.lock().unwrap() = Some(characteristic);
+                return Ok(());
+            }
+        }
+
+        Err(Error::NotSupported(String::from("Couldn't find the notify characteristic")))
+    }
+
+    async fn unsubscribe(&self) -> Result<()> {
+        let characteristic = self.characteristic.lock().unwrap().take();
+        match characteristic {
+            Some(characteristic) => { Ok(self.peripheral.unsubscribe(&characteristic).await?) }
+            None => { Ok(()) }
+        }
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        Ok(self.peripheral.disconnect().await?)
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.peripheral.is_connected().await?)
+    }
+
+    async fn write_frame(&self, frame: &[u8]) -> Result<()> {
+        let characteristic = self.characteristic.lock().unwrap().clone().ok_or(Error::NotConnected)?;
+        Ok(self.peripheral.write(&characteristic, frame, WriteType::WithoutResponse).await?)
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        let notification_stream = self.peripheral.notifications().await?;
+        let notify_characteristic_uuid = self.notify_characteristic_uuid;
+
+        Ok(Box::pin(notification_stream.filter_map(move |data| async move {
+            if data.uuid == notify_characteristic_uuid {
+                Some(data.value)
+            } else {
+                None
+            }
+        })))
+    }
+}
+
+impl From<btleplug::Error> for Error {
+    fn from(error: btleplug::Error) -> Self {
+        match error {
+            btleplug::Error::NotConnected => { Error::NotConnected }
+            btleplug::Error::NotSupported(reason) => { Error::NotSupported(reason) }
+            btleplug::Error::TimedOut(duration) => { Error::TimedOut(duration) }
+            error => { Error::Other(error.to_string().into()) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| String::from(*arg)).collect()
+    }
+
+    #[tokio::test]
+    async fn the_connect_fallback_gets_the_address() {
+        run_connect_fallback(&command(&["true", "{address}"]), "AA:BB:CC:DD:EE:01").await.unwrap();
+        // Only succeeds if the placeholder was replaced.
+        let check = command(&["sh", "-c", "test \"$1\" = AA:BB:CC:DD:EE:01", "sh", "{address}"]);
+        run_connect_fallback(&check, "AA:BB:CC:DD:EE:01").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_failing_connect_fallback_is_an_error() {
+        let err = run_connect_fallback(&command(&["false", "--device={address}"]), "AA:BB:CC:DD:EE:01").await.unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with(r#"Connect fallback ["false", "--device=AA:BB:CC:DD:EE:01"] failed"#), "{}", message);
+    }
+}
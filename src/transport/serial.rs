@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use super::{Error, NotificationStream, Result, Transport};
+
+/// The Bluno's serial port runs at this rate unless the sketch changes it.
+pub(crate) const DEFAULT_BAUD_RATE: u32 = 115_200;
+/// How many reads are buffered for a slow listener before old ones are dropped.
+const NOTIFICATION_BUFFER_SIZE: usize = 64;
+const READ_BUFFER_SIZE: usize = 64;
+
+/// Talks to a light wired up over USB, the Bluno's serial port carries the same frames as its BLE
+/// characteristic.
+#[derive(Clone)]
+pub(crate) struct SerialTransport {
+    path: String,
+    baud_rate: u32,
+    writer: Arc<Mutex<Option<WriteHalf<SerialStream>>>>,
+    read_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    notification_tx: broadcast::Sender<Vec<u8>>,
+}
+
+impl SerialTransport {
+    pub fn new(path: &str, baud_rate: u32) -> Self {
+        let (notification_tx, _) = broadcast::channel(NOTIFICATION_BUFFER_SIZE);
+
+        SerialTransport {
+            path: String::from(path),
+            baud_rate,
+            writer: Arc::new(Mutex::new(None)),
+            read_handle: Arc::new(Mutex::new(None)),
+            notification_tx,
+        }
+    }
+
+    async fn read_until_closed(mut reader: ReadHalf<SerialStream>, writer: Arc<Mutex<Option<WriteHalf<SerialStream>>>>, notification_tx: broadcast::Sender<Vec<u8>>) {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        loop {
+            match reader.read(&mut buffer).await {
+                Ok(0) => { break }
+                Ok(length) => {
+                    // Nobody listening isn't an error, the data is just dropped.
+                    let _ = notification_tx.send(buffer[..length].to_vec());
+                }
+                Err(err) => {
+                    eprintln!("Error reading from serial port: {}", err);
+                    break;
+                }
+            }
+        }
+
+        // Without a reader the port is no use to us, make sure the next write reconnects.
+        *writer.lock().await = None;
+    }
+}
+
+#[async_trait]
+impl Transport for SerialTransport {
+    fn address(&self) -> String {
+        self.path.clone()
+    }
+
+    async fn connect(&self) -> Result<()> {
+        let port = tokio_serial::new(&self.path, self.baud_rate)
+            .open_native_async()
+            .map_err(|err| Error::Other(Box::new(err)))?;
+        let (reader, writer) = tokio::io::split(port);
+
+        *self.writer.lock().await = Some(writer);
+        let read_writer = self.writer.clone();
+        let notification_tx = self.notification_tx.clone();
+        let read_handle = tokio::spawn(Self::read_until_closed(reader, read_writer, notification_tx));
+        if let Some(previous_handle) = self.read_handle.lock().await.replace(read_handle) {
+            previous_handle.abort();
+        }
+
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        if let Some(read_handle) = self.read_handle.lock().await.take() {
+            read_handle.abort();
+        }
+        *self.writer.lock().await = None;
+
+        Ok(())
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.writer.lock().await.is_some())
+    }
+
+    async fn write_frame(&self, frame: &[u8]) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        let port = writer.as_mut().ok_or(Error::NotConnected)?;
+        port.write_all(frame).await.map_err(|err| Error::Other(Box::new(err)))?;
+        port.flush().await.map_err(|err| Error::Other(Box::new(err)))
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        Ok(super::broadcast_notifications(self.notification_tx.subscribe()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use tokio::sync::mpsc;
+    use tokio_serial::SerialPort;
+
+    use crate::decoder::{HomeLightDecoder, HomeLightMessage, HomeLightMessageType};
+    use crate::peripheral::Command;
+
+    #[tokio::test]
+    async fn frames_cross_a_pty_pair() {
+        let (mut light_side, hub_side) = SerialStream::pair().unwrap();
+        let transport = SerialTransport::new(&hub_side.name().unwrap(), DEFAULT_BAUD_RATE);
+        transport.connect().await.unwrap();
+        assert!(transport.is_connected().await.unwrap());
+        let mut notifications = transport.notifications().await.unwrap();
+
+        let frame = Command::GetDeviceInfo.get_raw_data();
+        transport.write_frame(&frame).await.unwrap();
+        let mut received = vec![0; frame.len()];
+        light_side.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, frame);
+
+        let message = HomeLightMessage { message_type: HomeLightMessageType::DeviceColor, data: vec![1, 2, 3] };
+        light_side.write_all(&message.get_raw_data().unwrap()).await.unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut decoder = HomeLightDecoder::new(tx);
+        while rx.is_empty() {
+            decoder.consume_data_packet(&notifications.next().await.unwrap());
+        }
+        assert_eq!(rx.recv().await.unwrap().data, vec![1, 2, 3]);
+
+        transport.disconnect().await.unwrap();
+        assert!(!transport.is_connected().await.unwrap());
+    }
+}
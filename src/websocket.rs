@@ -0,0 +1,69 @@
+use std::pin::Pin;
+
+use futures::future::BoxFuture;
+use futures::Future;
+use rocket::data::{IoHandler, IoStream};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::tokio::io;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+
+pub(crate) type Socket = WebSocketStream<IoStream>;
+
+/// Request guard for a WebSocket handshake, Rocket takes care of the upgrade itself once a route
+/// responds with a `Channel`.
+pub(crate) struct WebSocket {
+    key: String,
+}
+
+/// Responds to a WebSocket handshake and hands the connection to a handler.
+pub(crate) struct Channel {
+    key: String,
+    handler: Box<dyn FnOnce(Socket) -> BoxFuture<'static, ()> + Send>,
+}
+
+impl WebSocket {
+    /// Accepts the connection, `handler` runs for as long as it should stay open.
+    pub fn channel<F, Fut>(self, handler: F) -> Channel
+        where F: FnOnce(Socket) -> Fut + Send + 'static, Fut: Future<Output = ()> + Send + 'static
+    {
+        Channel { key: self.key, handler: Box::new(move |socket| Box::pin(handler(socket))) }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebSocket {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let is_upgrade = headers.get_one("Upgrade").map(|upgrade| upgrade.eq_ignore_ascii_case("websocket")).unwrap_or(false);
+        match headers.get_one("Sec-WebSocket-Key") {
+            Some(key) if is_upgrade => { Outcome::Success(WebSocket { key: String::from(key) }) }
+            _ => { Outcome::Error((Status::UpgradeRequired, "Expected a WebSocket handshake")) }
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Channel {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .raw_header("Sec-WebSocket-Accept", derive_accept_key(self.key.as_bytes()))
+            .upgrade("websocket", self)
+            .ok()
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for Channel {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let channel = Pin::into_inner(self);
+        let socket = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+        (channel.handler)(socket).await;
+
+        Ok(())
+    }
+}